@@ -3,29 +3,76 @@ pub struct Config {
     pub text: String,
     pub margin: u32,
     pub width: u32,
+    pub page_height: Option<u32>,
+    pub columns: Option<u32>,
+    pub gutter: Option<u32>,
+    pub output: Option<String>,
+    pub hyphenation: Option<String>,
+    pub align: Option<String>,
+    pub last_line: Option<String>,
+    pub local_variations: Option<bool>,
+    pub shaper: Option<String>,
 
     pub text_color: u32,
     pub bg_color: u32,
 
     pub font: FontConfig,
+    pub demerits: Option<DemeritsConfig>,
+    pub kashida: Option<KashidaConfig>,
+    pub parallel: Option<ParallelConfig>,
 }
 
 #[derive(facet::Facet)]
 pub struct FontConfig {
     pub path: String,
+    pub index: Option<u32>,
     pub size: f32,
     pub line_height: f32,
+    pub fallbacks: Option<Vec<String>>,
+    pub instance: Option<String>,
+
+    pub script: Option<String>,
+    pub language: Option<String>,
+    pub direction: Option<String>,
 
     pub features: Option<Vec<String>>,
+    pub alternates: Option<String>,
     pub variations: Option<Vec<VariationConfig>>,
 }
 
 #[derive(facet::Facet)]
 pub struct VariationConfig {
     pub name: String,
-    pub min: f32,
-    pub max: f32,
-    pub rest: f32,
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub rest: Option<f32>,
+    pub weight: Option<f32>,
+}
+
+#[derive(facet::Facet)]
+pub struct ParallelConfig {
+    pub text: String,
+    pub placement: Option<String>,
+    pub hyphenation: Option<String>,
+    pub font: FontConfig,
+}
+
+#[derive(facet::Facet)]
+pub struct DemeritsConfig {
+    pub line_penalty: Option<usize>,
+    pub fitness_mismatch: Option<usize>,
+    pub variation_difference: Option<usize>,
+    pub hyphen_penalty: Option<usize>,
+    pub consecutive_hyphens: Option<usize>,
+}
+
+#[derive(facet::Facet)]
+pub struct KashidaConfig {
+    pub max_per_word: Option<usize>,
+    pub max_per_line: Option<usize>,
+    pub priority: Option<Vec<String>>,
+    pub avoid_last_word: Option<bool>,
+    pub order: Option<String>,
 }
 
 pub fn read_config(args: &mut pico_args::Arguments) -> Result<Config, Box<dyn std::error::Error>> {
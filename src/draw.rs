@@ -1,94 +1,367 @@
 use crate::{
-    logic::{line_break, Variation, VariationKind},
-    shaper::Shaper,
+    fvar::{parse_face, Coordinates},
+    layout::{layout, layout_parallel, LayoutOptions, Placement},
+    logic::{Alignment, Demerits, KashidaPolicy, LastLine, Variation, VariationKind},
+    pdf::PdfDocument,
+    shaper::{Backend, Direction},
+    svg::SvgDocument,
 };
-use ab_glyph::{self as ab, Font as _, ScaleFont as _};
+use ab_glyph::{self as ab, Font as _, ScaleFont as _, VariableFont as _};
 use image::{GenericImageView as _, Rgba, RgbaImage};
 use imageproc::drawing::Canvas as _;
 use resvg::{tiny_skia::Pixmap, usvg};
-use std::path::Path;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct ImageConfig {
     pub margin: u32,
     pub img_width: u32,
     pub font_size: f32,
     pub line_height: f32,
+    /// Feature with wider alternates of letters, such as `jalt`.
+    pub alternates: Option<[u8; 4]>,
     pub txt_color: [u8; 4],
     pub bkg_color: [u8; 4],
+    pub format: OutputFormat,
+    pub demerits: Demerits,
+    pub hyphenation: Option<hypher::Lang>,
+    pub alignment: Alignment,
+    pub last_line: LastLine,
+    pub kashida: KashidaPolicy,
+    /// Height of each page, margins included. A single page fits all the text if `None`.
+    pub page_height: Option<u32>,
+    pub columns: u32,
+    /// Space between columns.
+    pub gutter: u32,
+    /// Vary each word on its own rather than whole lines.
+    pub local_variations: bool,
+    pub shaper: Backend,
+    pub script: Option<unicode_script::Script>,
+    /// BCP 47 language tag.
+    pub language: Option<String>,
+    pub direction: Option<Direction>,
+}
+
+/// A second text drawn along the main one, such as a translation, with its own fonts.
+/// Its paragraphs are paired with the main text's in order.
+#[derive(Clone, Debug)]
+pub struct ParallelText {
+    pub text_path: PathBuf,
+    /// The primary font followed by its fallbacks, with the index of the face to use.
+    pub fonts: Vec<(PathBuf, u32)>,
+    pub features: Vec<String>,
+    pub alternates: Option<[u8; 4]>,
+    pub script: Option<unicode_script::Script>,
+    pub language: Option<String>,
+    pub direction: Option<Direction>,
+    pub variations: Vec<Variation>,
+    pub coordinates: Coordinates,
+    pub font_size: f32,
+    pub line_height: f32,
+    pub hyphenation: Option<hypher::Lang>,
+    pub placement: Placement,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum OutputFormat {
+    #[default]
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(Self::Png),
+            "svg" => Ok(Self::Svg),
+            "pdf" => Ok(Self::Pdf),
+            _ => Err(format!("Unknown output format: {s}")),
+        }
+    }
 }
 
+/// `fonts` is the primary font followed by its fallbacks, each with the index of the
+/// face to use. Faces other than 0 are for font collections (`.ttc`, `.otc`).
+///
+/// A `parallel` text is laid out on a single page in one column.
 pub fn run(
     text_path: impl AsRef<Path>,
-    font_path: impl AsRef<Path>,
+    fonts: &[(impl AsRef<Path>, u32)],
     features: &[String],
     variations: Vec<Variation>,
-    config @ ImageConfig {
+    coordinates: &[([u8; 4], f32)],
+    config: ImageConfig,
+    parallel: Option<ParallelText>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ImageConfig {
         margin,
         img_width,
         font_size,
         line_height,
+        alternates,
+        txt_color,
         bkg_color,
-        ..
-    }: ImageConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
+        format,
+        demerits,
+        hyphenation,
+        alignment,
+        last_line,
+        kashida,
+        page_height,
+        columns,
+        gutter,
+        local_variations,
+        shaper,
+        script,
+        language,
+        direction,
+    } = config.clone();
     let full_text = std::fs::read_to_string(text_path.as_ref())?;
-    let font_data = std::fs::read(font_path)?;
+    let fonts_data = read_fonts(fonts)?;
 
-    // let mut shaper = crate::shaper::HarfBuzz::new(&font_data);
-    let mut shaper = crate::shaper::RustBuzz::new(&font_data, features);
+    let options = LayoutOptions {
+        line_height,
+        features: features.to_vec(),
+        alternates,
+        variations,
+        coordinates: coordinates.to_vec(),
+        demerits,
+        hyphenation,
+        alignment,
+        last_line,
+        kashida,
+        page_height: page_height.map(|h| h.saturating_sub(2 * margin)),
+        columns,
+        gutter,
+        local: local_variations,
+        shaper,
+        script,
+        language,
+        direction,
+        ..LayoutOptions::new(img_width - 2 * margin, font_size)
+    };
+    let parallel_text;
+    let parallel_fonts_data;
+    let fonts_slices = font_slices(&fonts_data);
 
-    let mut ab_font = ab::FontRef::try_from_slice(&font_data)?;
-    let ab_scale = ab_font.pt_to_px_scale(font_size).unwrap();
+    let mut fonts = render_fonts(&fonts_data, font_size)?;
 
-    let ab_scaled_font = ab_font.as_scaled(ab_scale);
-    let scale_factor = ab_scaled_font.scale_factor();
-    let ascent = ab_scaled_font.ascent();
+    let layout = match parallel {
+        None => layout(&full_text, &fonts_slices, options)?,
+        Some(_) if page_height.is_some() || columns > 1 => {
+            return Err("Parallel text is laid out on a single page in one column.".into())
+        }
+        Some(parallel) => {
+            parallel_text = std::fs::read_to_string(&parallel.text_path)?;
+            parallel_fonts_data = read_fonts(&parallel.fonts)?;
+            fonts.extend(render_fonts(&parallel_fonts_data, parallel.font_size)?);
+
+            let parallel_options = LayoutOptions {
+                font_size: parallel.font_size,
+                line_height: parallel.line_height,
+                features: parallel.features,
+                alternates: parallel.alternates,
+                script: parallel.script,
+                language: parallel.language,
+                direction: parallel.direction,
+                variations: parallel.variations,
+                coordinates: parallel.coordinates,
+                hyphenation: parallel.hyphenation,
+                ..options.clone()
+            };
+            layout_parallel(
+                (&full_text, &fonts_slices, options),
+                (
+                    &parallel_text,
+                    &font_slices(&parallel_fonts_data),
+                    parallel_options,
+                ),
+                parallel.placement,
+            )?
+        }
+    };
 
-    let lines = line_break(
-        &mut shaper,
-        &full_text,
-        ((img_width - 2 * margin) as f32 / scale_factor.horizontal) as u32,
-        variations,
-    )?;
+    let img_height = layout.height() + 2 * margin;
+
+    // each page of an image format is its own file
+    let page_path = |page: usize, extension: &str| {
+        let path = text_path.as_ref();
+        match path.file_stem() {
+            Some(stem) if layout.pages.len() > 1 => path.with_file_name(format!(
+                "{}-{}.{extension}",
+                stem.to_string_lossy(),
+                page + 1
+            )),
+            _ => path.with_extension(extension),
+        }
+    };
 
-    let line_height = (ab_scaled_font.height() * line_height) as u32;
+    let mut pdf: Option<PdfDocument> = None;
+    for (page_idx, page) in layout.pages.iter().enumerate() {
+        let mut canvas = match format {
+            OutputFormat::Png => Canvas::Raster(RgbaImage::from_pixel(
+                img_width,
+                img_height,
+                Rgba(bkg_color),
+            )),
+            OutputFormat::Svg => Canvas::Vector(SvgDocument::new(
+                img_width, img_height, txt_color, bkg_color,
+            )),
+            OutputFormat::Pdf => Canvas::Document(match pdf.take() {
+                Some(mut document) => {
+                    document.new_page();
+                    document
+                }
+                None => PdfDocument::new(img_width, img_height, txt_color, bkg_color),
+            }),
+        };
 
-    let mut canvas = RgbaImage::from_pixel(
-        img_width,
-        lines.len() as u32 * line_height + 2 * margin,
-        Rgba(bkg_color),
-    );
+        for line in &layout.lines[page.clone()] {
+            let glyphs = line
+                .glyphs
+                .iter()
+                .map(|g| PlacedGlyph {
+                    id: ab::GlyphId(g.id),
+                    font: g.font,
+                    position: ab::point(g.x + margin as f32, g.y + margin as f32),
+                    cluster: g.cluster,
+                    variations: line
+                        .words
+                        .iter()
+                        .find(|(range, _)| range.contains(&(g.cluster as usize)))
+                        .map_or(&line.variations, |(_, variations)| variations),
+                })
+                .collect::<Vec<_>>();
+
+            match &mut canvas {
+                Canvas::Raster(canvas) => write_in_image(canvas, &mut fonts, &glyphs, &config),
+                Canvas::Vector(document) => glyphs.into_iter().for_each(|g| {
+                    let RenderFont {
+                        font, scale_factor, ..
+                    } = fonts[g.font].with_variations(g.variations);
+                    document.push_glyph(font, g.id, g.position, *scale_factor);
+                }),
+                Canvas::Document(document) => {
+                    let added = line
+                        .kashidas
+                        .iter()
+                        .copied()
+                        .chain(line.hyphenated.then(|| line.text.len() - 1))
+                        .collect::<Vec<_>>();
+                    document.push_line(&mut fonts, &line.text, &glyphs, &added)
+                }
+            }
+        }
 
-    for (idx, line) in lines.into_iter().enumerate() {
-        let text_slice = {
-            let t = full_text[line.start_bp..line.end_bp].trim();
-            let c = kashida::find_kashidas(t, kashida::Script::Arabic);
-            kashida::place_kashidas(t, &c, line.kashida_count)
-        };
+        match canvas {
+            Canvas::Raster(mut canvas) => {
+                _ = draw_signature(&mut canvas, margin);
+                canvas.save(page_path(page_idx, "png"))?;
+            }
+            Canvas::Vector(document) => {
+                std::fs::write(page_path(page_idx, "svg"), document.finish())?;
+            }
+            Canvas::Document(document) => pdf = Some(document),
+        }
+    }
 
-        write_in_image(
-            &text_slice,
-            &mut canvas,
-            idx,
-            &mut ab_font,
-            &mut shaper,
-            line.variations,
-            config,
-            ScaledFontData {
-                line_height,
-                scale_factor,
-                ascent,
-                ab_scale,
-            },
-        );
+    if let Some(document) = pdf {
+        std::fs::write(text_path.as_ref().with_extension("pdf"), document.finish())?;
     }
 
-    _ = draw_signature(&mut canvas, margin);
+    Ok(())
+}
 
-    canvas.save(&text_path.as_ref().with_extension("png"))?;
+enum Canvas {
+    Raster(RgbaImage),
+    Vector(SvgDocument),
+    Document(PdfDocument),
+}
 
-    Ok(())
+/// A font prepared for drawing at the configured size.
+pub(crate) struct RenderFont<'f> {
+    pub font: ab::FontRef<'f>,
+    pub scale: ab::PxScale,
+    pub scale_factor: ab::PxScaleFactor,
+}
+
+impl RenderFont<'_> {
+    /// Sets the font's axes to the values of `variations`, for the glyphs drawn next.
+    pub fn with_variations(
+        &mut self,
+        variations: &[Variation],
+    ) -> &Self {
+        for v in variations {
+            if let VariationKind::Axis(tag) = v.kind {
+                self.font.set_variation(&tag, v.current_value);
+            }
+        }
+        self
+    }
+}
+
+/// A font's data and the index of the face to use.
+type FontData = (Vec<u8>, u32);
+
+/// Reads each font and checks that it has the face given with it.
+fn read_fonts(
+    fonts: &[(impl AsRef<Path>, u32)]
+) -> Result<Vec<FontData>, Box<dyn std::error::Error>> {
+    fonts
+        .iter()
+        .map(|(path, index)| {
+            let path = path.as_ref();
+            let data = std::fs::read(path)
+                .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+            parse_face(&data, *index).map_err(|e| format!("{}: {e}", path.display()))?;
+            Ok((data, *index))
+        })
+        .collect()
+}
+
+fn font_slices(fonts_data: &[FontData]) -> Vec<(&[u8], u32)> {
+    fonts_data
+        .iter()
+        .map(|(data, index)| (data.as_slice(), *index))
+        .collect()
+}
+
+fn render_fonts(
+    fonts_data: &[FontData],
+    font_size: f32,
+) -> Result<Vec<RenderFont<'_>>, Box<dyn std::error::Error>> {
+    fonts_data
+        .iter()
+        .map(|(data, index)| {
+            let font = ab::FontRef::try_from_slice_and_index(data, *index)?;
+            let scale = font
+                .pt_to_px_scale(font_size)
+                .ok_or("Font does not have units per em.")?;
+            let scale_factor = font.as_scaled(scale).scale_factor();
+            Ok(RenderFont {
+                font,
+                scale,
+                scale_factor,
+            })
+        })
+        .collect()
+}
+
+pub(crate) struct PlacedGlyph<'v> {
+    pub id: ab::GlyphId,
+    /// Index into the fonts the line is drawn with.
+    pub font: usize,
+    pub position: ab::Point,
+    /// Byte index into the line's text, as given by the shaper.
+    pub cluster: u32,
+    /// Values the glyph's font is set to: its word's, or else its line's.
+    pub variations: &'v [Variation],
 }
 
 fn draw_signature(
@@ -122,75 +395,40 @@ fn draw_signature(
     Ok(())
 }
 
-struct ScaledFontData {
-    line_height: u32,
-    scale_factor: ab::PxScaleFactor,
-    ascent: f32,
-    ab_scale: ab::PxScale,
-}
-
-#[allow(clippy::too_many_arguments)]
-fn write_in_image<'a>(
-    text_slice: &str,
+fn write_in_image(
     canvas: &mut RgbaImage,
-    line_number: usize,
-    ab_font: &mut (impl ab::Font + ab::VariableFont),
-    shaper: &mut impl Shaper<'a>,
-    variations: Vec<Variation>,
-    ImageConfig {
-        margin, txt_color, ..
-    }: ImageConfig,
-    ScaledFontData {
-        line_height,
-        scale_factor,
-        ascent,
-        ab_scale,
-    }: ScaledFontData,
+    fonts: &mut [RenderFont],
+    glyphs: &[PlacedGlyph],
+    &ImageConfig { txt_color, .. }: &ImageConfig,
 ) {
-    variations
-        .iter()
-        .filter_map(|v| match v.kind {
-            VariationKind::Axis(tag) => Some((tag, v.current_value)),
-            VariationKind::Spacing => None,
-        })
-        .for_each(|(tag, value)| {
-            ab_font.set_variation(&tag, value);
-        });
-
-    let shaped_text = shaper.shape_text(text_slice, &variations);
-
-    let centered_line_offset = (canvas.width() - 2 * margin).saturating_sub(
-        shaped_text
-            .iter()
-            .map(|g| g.x_advance as f32 * scale_factor.horizontal)
-            .sum::<f32>() as u32,
-    ) / 2;
-
-    let mut caret = 0;
     let mut colored_glyphs = vec![];
 
-    for glyph in shaped_text {
-        let gl = ab::GlyphId(glyph.codepoint as u16).with_scale_and_position(
-            ab_scale,
-            ab::point(
-                (caret + glyph.x_offset) as f32 * scale_factor.horizontal,
-                ascent - (glyph.y_offset as f32 * scale_factor.vertical),
-            ),
-        );
+    for &PlacedGlyph {
+        id,
+        font,
+        position,
+        variations,
+        ..
+    } in glyphs
+    {
+        let RenderFont {
+            font: ab_font,
+            scale,
+            ..
+        } = fonts[font].with_variations(variations);
+        let gl = id.with_scale_and_position(*scale, position);
 
-        caret += glyph.x_advance;
         let Some(outlined_glyph) = ab_font.outline_glyph(gl) else {
             // gl is whitespace?
             continue;
         };
 
         let bb = outlined_glyph.px_bounds();
-        let bbx = (bb.min.x as i32).saturating_add_unsigned(margin + centered_line_offset);
-        let bby =
-            (bb.min.y as i32).saturating_add_unsigned(margin + line_number as u32 * line_height);
+        let bbx = bb.min.x as i32;
+        let bby = bb.min.y as i32;
         if let Some(colored_glyph) = ab_font
-            .glyph_svg_image(ab::GlyphId(glyph.codepoint as u16))
-            .and_then(|svg| svg_data_to_glyph(svg.data, bb, glyph.codepoint))
+            .glyph_svg_image(id)
+            .and_then(|svg| svg_data_to_glyph(svg.data, bb, id.0 as u32))
         {
             colored_glyphs.push((bbx, bby, colored_glyph));
         } else {
@@ -231,3 +469,61 @@ fn svg_data_to_glyph(
     resvg::render_node(node, transform, &mut pixmap.as_mut());
     RgbaImage::from_raw(size.width(), size.height(), pixmap.data().to_vec())
 }
+
+#[derive(Clone, Copy)]
+pub(crate) enum PathOp {
+    MoveTo(ab::Point),
+    LineTo(ab::Point),
+    QuadTo(ab::Point, ab::Point),
+    CubicTo(ab::Point, ab::Point, ab::Point),
+    Close,
+}
+
+/// The glyph's outline in page coordinates (y-down), positioned at the baseline
+/// origin `position`. Uses the font's current variation coordinates.
+pub(crate) fn glyph_path(
+    font: &impl ab::Font,
+    id: ab::GlyphId,
+    position: ab::Point,
+    scale_factor: ab::PxScaleFactor,
+) -> Option<Vec<PathOp>> {
+    let outline = font.outline(id)?;
+
+    // font units are y-up
+    let tr = |p: ab::Point| {
+        ab::point(
+            position.x + p.x * scale_factor.horizontal,
+            position.y - p.y * scale_factor.vertical,
+        )
+    };
+
+    let mut path = vec![];
+    let mut last = None;
+    for curve in outline.curves {
+        let (start, end) = match curve {
+            ab::OutlineCurve::Line(p0, p1) => (p0, p1),
+            ab::OutlineCurve::Quad(p0, _, p2) => (p0, p2),
+            ab::OutlineCurve::Cubic(p0, _, _, p3) => (p0, p3),
+        };
+
+        if last != Some(start) {
+            if last.is_some() {
+                path.push(PathOp::Close);
+            }
+            path.push(PathOp::MoveTo(tr(start)));
+        }
+
+        path.push(match curve {
+            ab::OutlineCurve::Line(_, p1) => PathOp::LineTo(tr(p1)),
+            ab::OutlineCurve::Quad(_, p1, p2) => PathOp::QuadTo(tr(p1), tr(p2)),
+            ab::OutlineCurve::Cubic(_, p1, p2, p3) => PathOp::CubicTo(tr(p1), tr(p2), tr(p3)),
+        });
+
+        last = Some(end);
+    }
+
+    last.is_some().then(|| {
+        path.push(PathOp::Close);
+        path
+    })
+}
@@ -0,0 +1,327 @@
+use crate::logic::Variation;
+use rustybuzz::ttf_parser::{self, Tag};
+
+/// Values of font axes, by tag.
+pub type Coordinates = Vec<([u8; 4], f32)>;
+
+/// A variation axis as declared in the font's `fvar` table.
+#[derive(Clone, Copy, Debug)]
+pub struct Axis {
+    pub tag: [u8; 4],
+    pub min: f32,
+    pub default: f32,
+    pub max: f32,
+}
+
+/// Face `index` of a font. Collections (`.ttc`, `.otc`) hold several faces, and a
+/// single font only has face 0.
+pub(crate) fn parse_face(
+    font_data: &[u8],
+    index: u32,
+) -> Result<ttf_parser::Face<'_>, Box<dyn std::error::Error>> {
+    match ttf_parser::fonts_in_collection(font_data) {
+        None if index > 0 => {
+            return Err(format!(
+                "No face {index} in the font. It is not a collection, so its only face is 0."
+            )
+            .into())
+        }
+        Some(count) if index >= count => {
+            return Err(format!(
+                "No face {index} in the collection. Its {count} faces are numbered from 0."
+            )
+            .into())
+        }
+        _ => {}
+    }
+    ttf_parser::Face::parse(font_data, index)
+        .map_err(|e| format!("Could not read face {index} of the font: {e}.").into())
+}
+
+/// The variation axes of face `index` of the font. Empty for static fonts.
+pub fn font_axes(
+    font_data: &[u8],
+    index: u32,
+) -> Result<Vec<Axis>, Box<dyn std::error::Error>> {
+    let face = parse_face(font_data, index)?;
+
+    Ok(face
+        .variation_axes()
+        .into_iter()
+        .map(|a| Axis {
+            tag: a.tag.to_bytes(),
+            min: a.min_value,
+            default: a.def_value,
+            max: a.max_value,
+        })
+        .collect())
+}
+
+/// Coordinates of a named instance, such as "Bold" or "Condensed". Named instances in
+/// `fvar` are looked up first. Otherwise the name's words are matched to the axis value
+/// names in `STAT`, so "Bold Condensed" works for fonts that only name each axis.
+pub fn instance_coordinates(
+    font_data: &[u8],
+    index: u32,
+    name: &str,
+) -> Result<Coordinates, Box<dyn std::error::Error>> {
+    let face = parse_face(font_data, index)?;
+
+    let instances = named_instances(&face);
+    if let Some((_, coordinates)) = instances.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        return Ok(coordinates.clone());
+    }
+
+    let values = stat_axis_values(&face);
+    let mut coordinates: Coordinates = vec![];
+    for word in name.split_whitespace() {
+        let (_, value) = values
+            .iter()
+            .find(|(n, _)| n.replace(' ', "").eq_ignore_ascii_case(word))
+            .ok_or_else(|| {
+                let known = instances
+                    .iter()
+                    .map(|(n, _)| n.as_str())
+                    .collect::<Vec<_>>();
+                match known.is_empty() {
+                    true => format!("Unknown instance {name}. The font has no named instances."),
+                    false => format!(
+                        "Unknown instance {name}. The font has {}.",
+                        known.join(", ")
+                    ),
+                }
+            })?;
+
+        for &(tag, v) in value {
+            match coordinates.iter_mut().find(|(t, _)| *t == tag) {
+                Some(c) => c.1 = v,
+                None => coordinates.push((tag, v)),
+            }
+        }
+    }
+
+    Ok(coordinates)
+}
+
+fn name_string(
+    face: &ttf_parser::Face,
+    name_id: u16,
+) -> Option<String> {
+    face.names()
+        .into_iter()
+        .filter(|n| n.name_id == name_id && n.is_unicode())
+        .find_map(|n| {
+            // UTF-16BE
+            let units = n
+                .name
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>();
+            String::from_utf16(&units).ok()
+        })
+}
+
+fn read_u16(
+    data: &[u8],
+    at: usize,
+) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(
+    data: &[u8],
+    at: usize,
+) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_tag(
+    data: &[u8],
+    at: usize,
+) -> Option<[u8; 4]> {
+    data.get(at..at + 4)?.try_into().ok()
+}
+
+/// 16.16 fixed point.
+fn read_fixed(
+    data: &[u8],
+    at: usize,
+) -> Option<f32> {
+    Some(read_u32(data, at)? as i32 as f32 / 65536.0)
+}
+
+/// Named instances from the `fvar` table, which ttf_parser does not expose.
+fn named_instances(face: &ttf_parser::Face) -> Vec<(String, Coordinates)> {
+    let parse = || {
+        let data = face.raw_face().table(Tag::from_bytes(b"fvar"))?;
+        let axes_offset = read_u16(data, 4)? as usize;
+        let axis_count = read_u16(data, 8)? as usize;
+        let axis_size = read_u16(data, 10)? as usize;
+        let instance_count = read_u16(data, 12)? as usize;
+        let instance_size = read_u16(data, 14)? as usize;
+
+        let tags = (0..axis_count)
+            .map(|i| read_tag(data, axes_offset + i * axis_size))
+            .collect::<Option<Vec<_>>>()?;
+
+        let instances_offset = axes_offset + axis_count * axis_size;
+        (0..instance_count)
+            .map(|i| {
+                let at = instances_offset + i * instance_size;
+                let name = name_string(face, read_u16(data, at)?)?;
+                let coordinates = tags
+                    .iter()
+                    .enumerate()
+                    .map(|(j, &tag)| Some((tag, read_fixed(data, at + 4 + 4 * j)?)))
+                    .collect::<Option<Vec<_>>>()?;
+                Some((name, coordinates))
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+
+    parse().unwrap_or_default()
+}
+
+/// Axis value names from the `STAT` table, with the coordinates they stand for.
+fn stat_axis_values(face: &ttf_parser::Face) -> Vec<(String, Coordinates)> {
+    let parse = || {
+        let data = face.raw_face().table(Tag::from_bytes(b"STAT"))?;
+        let design_axis_size = read_u16(data, 4)? as usize;
+        let design_axis_count = read_u16(data, 6)? as usize;
+        let design_axes_offset = read_u32(data, 8)? as usize;
+        let value_count = read_u16(data, 12)? as usize;
+        let values_offset = read_u32(data, 14)? as usize;
+
+        let tags = (0..design_axis_count)
+            .map(|i| read_tag(data, design_axes_offset + i * design_axis_size))
+            .collect::<Option<Vec<_>>>()?;
+        let tag = |index: u16| tags.get(index as usize).copied();
+
+        (0..value_count)
+            .map(|i| {
+                let at = values_offset + read_u16(data, values_offset + 2 * i)? as usize;
+                let name = name_string(face, read_u16(data, at + 6)?)?;
+                let coordinates = match read_u16(data, at)? {
+                    // single value, range with a nominal value, and linked value
+                    1..=3 => vec![(tag(read_u16(data, at + 2)?)?, read_fixed(data, at + 8)?)],
+                    4 => (0..read_u16(data, at + 2)? as usize)
+                        .map(|k| {
+                            let record = at + 8 + 6 * k;
+                            Some((tag(read_u16(data, record)?)?, read_fixed(data, record + 2)?))
+                        })
+                        .collect::<Option<Vec<_>>>()?,
+                    _ => return Some(None),
+                };
+                Some(Some((name, coordinates)))
+            })
+            .collect::<Option<Vec<_>>>()
+    };
+
+    parse().into_iter().flatten().flatten().collect()
+}
+
+impl Variation {
+    /// A variation along one of the font's `axes`. Missing values default to the
+    /// axis' own range and default, and given ones must lie within it.
+    ///
+    /// To range around a named instance, set the axes' defaults to its coordinates.
+    pub fn from_font_axis(
+        axes: &[Axis],
+        name: &str,
+        min: Option<f32>,
+        max: Option<f32>,
+        rest: Option<f32>,
+    ) -> Result<Self, String> {
+        let tag: [u8; 4] = name
+            .as_bytes()
+            .try_into()
+            .map_err(|_| format!("Axis tags are four characters: {name}"))?;
+
+        let axis = axes.iter().find(|a| a.tag == tag).ok_or_else(|| {
+            let known = axes
+                .iter()
+                .map(|a| String::from_utf8_lossy(&a.tag).into_owned())
+                .collect::<Vec<_>>();
+            match known.is_empty() {
+                true => format!("Unknown axis {name}. The font is not variable."),
+                false => format!("Unknown axis {name}. The font has {}.", known.join(", ")),
+            }
+        })?;
+
+        let min = min.unwrap_or(axis.min);
+        let max = max.unwrap_or(axis.max);
+        // `clamp` below panics on these
+        if min > max || min.is_nan() || max.is_nan() {
+            return Err(format!(
+                "min is greater than max for axis {name}: {min}..{max}"
+            ));
+        }
+        let rest = rest.unwrap_or(axis.default.clamp(min, max));
+
+        if min < axis.min || max > axis.max {
+            return Err(format!(
+                "Axis {name} range {min}..{max} is outside the font's {}..{}",
+                axis.min, axis.max
+            ));
+        }
+        if !(min..=max).contains(&rest) {
+            return Err(format!(
+                "Axis {name} rest value {rest} is outside its range {min}..{max}"
+            ));
+        }
+
+        Ok(Self::new_axis(tag, min, max, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WDTH: Axis = Axis {
+        tag: *b"wdth",
+        min: 62.5,
+        default: 100.0,
+        max: 150.0,
+    };
+
+    #[test]
+    fn inverted_bounds_are_errors() {
+        let axis = |min, max, rest| Variation::from_font_axis(&[WDTH], "wdth", min, max, rest);
+
+        assert!(axis(Some(140.0), Some(80.0), None).is_err());
+        assert!(axis(Some(140.0), Some(80.0), Some(100.0)).is_err());
+        assert!(axis(Some(f32::NAN), None, None).is_err());
+        assert!(axis(Some(80.0), Some(140.0), None).is_ok());
+        // the default rest is brought into the range
+        let narrow = axis(Some(62.5), Some(80.0), None).unwrap();
+        assert_eq!(narrow.current_value, 80.0);
+    }
+
+    #[test]
+    fn instances_resolve_to_coordinates() {
+        let georama = include_bytes!("../fonts/Georama-VariableFont_wdth,wght.ttf");
+        let estedad = include_bytes!("../fonts/Estedad-FD[KSHD,wght].ttf");
+        let value = |coordinates: &Coordinates, tag: &[u8; 4]| {
+            coordinates.iter().find(|(t, _)| t == tag).map(|(_, v)| *v)
+        };
+
+        let bold = instance_coordinates(georama, 0, "Bold").unwrap();
+        assert_eq!(value(&bold, b"wdth"), Some(100.0));
+        assert_eq!(value(&bold, b"wght"), Some(700.0));
+        // not an fvar instance, put together from the STAT axis values
+        let condensed = instance_coordinates(georama, 0, "Condensed Bold").unwrap();
+        assert_eq!(value(&condensed, b"wdth"), Some(75.0));
+        assert_eq!(value(&condensed, b"wght"), Some(700.0));
+        let black = instance_coordinates(estedad, 0, "Black").unwrap();
+        assert_eq!(value(&black, b"wght"), Some(900.0));
+
+        let unknown = instance_coordinates(georama, 0, "Fat").unwrap_err();
+        assert!(unknown.to_string().contains("Thin, ExtraLight"));
+        let static_font = include_bytes!("../fonts/Raqq.ttf");
+        let unknown = instance_coordinates(static_font, 0, "Bold").unwrap_err();
+        assert!(unknown
+            .to_string()
+            .ends_with("The font has no named instances."));
+    }
+}
@@ -0,0 +1,816 @@
+use crate::{
+    fvar::Coordinates,
+    logic::{
+        line_break, Alignment, BreakOptions, Demerits, KashidaPolicy, LastLine, Variation,
+        VariationKind,
+    },
+    shaper::{
+        shape_line_local, words_shape_apart, AnyShaper, Backend, Cached, Direction, ShaperPool,
+        TextProperties,
+    },
+};
+use ab_glyph::{self as ab, Font as _, ScaleFont as _};
+use itertools::Itertools as _;
+use rayon::prelude::*;
+use std::{error::Error, ops::Range, str::FromStr};
+
+#[derive(Clone, Debug)]
+pub struct LayoutOptions {
+    /// Width of the text block in pixels.
+    pub width: u32,
+    pub font_size: f32,
+    /// Multiple of the font's height.
+    pub line_height: f32,
+    pub features: Vec<String>,
+    /// Feature with wider alternates of letters, such as `jalt`, used to fill lines
+    /// that variations cannot.
+    pub alternates: Option<[u8; 4]>,
+    pub variations: Vec<Variation>,
+    /// Coordinates of the axes that are not varied, such as those of a named instance.
+    pub coordinates: Coordinates,
+    pub demerits: Demerits,
+    /// Language to hyphenate words in. No hyphenation if `None`.
+    pub hyphenation: Option<hypher::Lang>,
+    pub alignment: Alignment,
+    pub last_line: LastLine,
+    pub kashida: KashidaPolicy,
+    /// Height of the text block on a page in pixels. All lines are on one page if `None`.
+    pub page_height: Option<u32>,
+    /// Number of columns the text block is split into. Lines are broken at the column width.
+    pub columns: u32,
+    /// Space between columns in pixels.
+    pub gutter: u32,
+    /// Vary each word on its own rather than whole lines.
+    pub local: bool,
+    pub shaper: Backend,
+    /// Script of all the text. Detected for each run if `None`.
+    pub script: Option<unicode_script::Script>,
+    /// BCP 47 tag of the text's language, such as `fa` or `ur`.
+    pub language: Option<String>,
+    /// Base direction of all paragraphs. Detected for each if `None`.
+    pub direction: Option<Direction>,
+}
+
+impl LayoutOptions {
+    /// A text block `width` pixels wide, set at `font_size` on a single page and
+    /// column. Lines are justified by the space between words alone.
+    pub fn new(
+        width: u32,
+        font_size: f32,
+    ) -> Self {
+        Self {
+            width,
+            font_size,
+            line_height: 1.0,
+            features: vec![],
+            alternates: None,
+            variations: vec![Variation::new_spacing(0.25, 1.25, 1.0)],
+            coordinates: vec![],
+            demerits: Demerits::default(),
+            hyphenation: None,
+            alignment: Alignment::default(),
+            last_line: LastLine::default(),
+            kashida: KashidaPolicy::default(),
+            page_height: None,
+            columns: 1,
+            gutter: 0,
+            local: false,
+            shaper: Backend::default(),
+            script: None,
+            language: None,
+            direction: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub lines: Vec<Line>,
+    /// Ranges of `lines` on each page. Glyph positions are relative to their page.
+    pub pages: Vec<Range<usize>>,
+    /// Ranges of `lines` in each column, across all pages.
+    pub columns: Vec<Range<usize>>,
+    pub width: u32,
+    pub page_height: Option<u32>,
+    /// Distance between consecutive baselines in pixels.
+    pub line_height: u32,
+    /// Distance between the top of a line and its baseline in pixels.
+    pub ascent: f32,
+}
+
+impl Layout {
+    /// Height of a page in pixels.
+    #[must_use]
+    pub fn height(&self) -> u32 {
+        self.page_height.unwrap_or(
+            self.columns.iter().map(|c| c.len()).max().unwrap_or(0) as u32 * self.line_height,
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// Byte range of the line in the source text.
+    pub range: Range<usize>,
+    /// The text that was shaped: the trimmed line with its hyphen and kashidas placed.
+    /// `Glyph::cluster` indexes into this string.
+    pub text: String,
+    /// Values the line is set at, with the fixed `LayoutOptions::coordinates` last.
+    pub variations: Vec<Variation>,
+    /// Values of the words that are varied on their own, with byte ranges into `text`.
+    /// Glyphs outside of these are set at `variations`.
+    pub words: Vec<(Range<usize>, Vec<Variation>)>,
+    /// Byte positions in `text` of the tatweels inserted to justify the line.
+    pub kashidas: Vec<usize>,
+    /// The line ends inside a word, and `text` ends with the hyphen added to it.
+    pub hyphenated: bool,
+    /// The line was fit to the layout's width, rather than set at rest values.
+    pub justified: bool,
+    pub glyphs: Vec<Glyph>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Glyph {
+    pub id: u16,
+    /// Index of the font the glyph is drawn from: 0 is the primary font, and the
+    /// rest are its fallbacks in order.
+    pub font: usize,
+    pub cluster: u32,
+    /// Position of the glyph's baseline origin in pixels, relative to the
+    /// top left corner of the page.
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Shapes and breaks `text` into lines of `options.width`, without rendering anything.
+///
+/// `fonts` is the primary font followed by its fallbacks, each as its data and the index
+/// of its face, which is 0 unless the font is a collection. Metrics are taken from the
+/// primary font.
+pub fn layout(
+    text: &str,
+    fonts: &[(&[u8], u32)],
+    options: LayoutOptions,
+) -> Result<Layout, Box<dyn Error>> {
+    let typesetter = Typesetter::new(text, fonts, &options)?;
+    layout_with(&typesetter, text, options)
+}
+
+/// The shapers of a text's fonts and the primary font's metrics, set up once so that
+/// the text's paragraphs can be laid out on their own and still share what the
+/// shapers cache.
+struct Typesetter<'f> {
+    shapers: ShaperPool<'f, Cached<AnyShaper<'f>>>,
+    scale_factor: ab::PxScaleFactor,
+    ascent: f32,
+    line_height: u32,
+}
+
+impl<'f> Typesetter<'f> {
+    /// `text` is sampled to tell whether words can be shaped apart.
+    fn new(
+        text: &str,
+        fonts: &[(&'f [u8], u32)],
+        options: &LayoutOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let &(font_data, font_index) = fonts.first().ok_or("No font given.")?;
+        let properties = TextProperties {
+            script: options.script,
+            language: options.language.clone(),
+            direction: options.direction,
+        };
+        let (fonts, features, coordinates) = (
+            fonts.to_vec(),
+            options.features.clone(),
+            options.coordinates.clone(),
+        );
+        let (backend, alternates) = (options.shaper, options.alternates);
+        let new_shaper = move || {
+            AnyShaper::new(
+                backend,
+                &fonts,
+                &features,
+                &coordinates,
+                alternates,
+                &properties,
+            )
+        };
+        let by_word = words_shape_apart(new_shaper()?, text);
+        let shapers = ShaperPool::new(move || {
+            Cached::new(
+                new_shaper().expect("the fonts were read for the first shaper"),
+                by_word,
+            )
+        });
+
+        let ab_font = ab::FontRef::try_from_slice_and_index(font_data, font_index)?;
+        let ab_scale = ab_font
+            .pt_to_px_scale(options.font_size)
+            .ok_or("Font does not have units per em.")?;
+
+        let ab_scaled_font = ab_font.as_scaled(ab_scale);
+        let line_height = (ab_scaled_font.height() * options.line_height) as u32;
+        if line_height == 0 {
+            return Err("Line height is zero. It is a multiple of the font's height.".into());
+        }
+
+        Ok(Self {
+            shapers,
+            scale_factor: ab_scaled_font.scale_factor(),
+            ascent: ab_scaled_font.ascent(),
+            line_height,
+        })
+    }
+}
+
+/// `layout` with the shapers and metrics of `typesetter`, which was set up for the
+/// same fonts and options.
+fn layout_with(
+    Typesetter {
+        shapers,
+        scale_factor,
+        ascent,
+        line_height,
+    }: &Typesetter,
+    text: &str,
+    LayoutOptions {
+        width,
+        variations,
+        coordinates,
+        demerits,
+        hyphenation,
+        alignment,
+        last_line,
+        kashida,
+        page_height,
+        columns: column_count,
+        gutter,
+        local,
+        direction,
+        alternates,
+        ..
+    }: LayoutOptions,
+) -> Result<Layout, Box<dyn Error>> {
+    let (scale_factor, ascent, line_height) = (*scale_factor, *ascent, *line_height);
+    if let Some(height) = page_height.filter(|&h| h < line_height) {
+        return Err(format!(
+            "Page height leaves {height}px for text, less than a line of {line_height}px. It includes the margins."
+        )
+        .into());
+    }
+
+    let column_count = column_count.max(1);
+    let column_width = width.saturating_sub(gutter * (column_count - 1)) / column_count;
+
+    let break_options = BreakOptions {
+        variations,
+        demerits,
+        hyphenation,
+        alignment,
+        last_line,
+        kashida,
+        alternates: alternates.is_some(),
+        local,
+        direction,
+    };
+    let lines = line_break(
+        shapers,
+        text,
+        (column_width as f32 / scale_factor.horizontal) as u32,
+        &break_options,
+    )?;
+
+    let starts = lines
+        .iter()
+        .map(|l| l.start_bp == 0 || text[..l.start_bp].ends_with("\n\n"))
+        .collect::<Vec<_>>();
+    let column_count = column_count as usize;
+
+    let per_column = page_height.map_or(lines.len(), |h| (h / line_height) as usize);
+    let pages = paginate(&starts, per_column * column_count);
+    let columns = balance_columns(&starts, &pages, (column_count, per_column));
+
+    let rtl_columns = direction.unwrap_or_else(|| Direction::of(text)) == Direction::Rtl;
+    let column_x = |position| column_x(position, (width, column_width, gutter), rtl_columns);
+
+    // the column each line is in, and the column's position on its page
+    let mut placement = vec![(0..0, 0); lines.len()];
+    for page in &pages {
+        for (position, column) in columns
+            .iter()
+            .filter(|c| page.contains(&c.start))
+            .enumerate()
+        {
+            for idx in column.clone() {
+                placement[idx] = (column.clone(), position);
+            }
+        }
+    }
+
+    // the fixed coordinates are appended as variations pinned to their value
+    let pinned = |variations: &[Variation]| {
+        variations
+            .iter()
+            .copied()
+            .chain(
+                coordinates
+                    .iter()
+                    .filter(|(tag, _)| {
+                        variations
+                            .iter()
+                            .all(|v| !matches!(v.kind, VariationKind::Axis(t) if t == *tag))
+                    })
+                    .map(|&(tag, value)| Variation::new_axis(tag, value, value, value)),
+            )
+            .collect::<Vec<_>>()
+    };
+
+    let lines = lines
+        .into_par_iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            let (column, position) = &placement[idx];
+            let text_slice = line.text(text);
+
+            let direction = direction.unwrap_or_else(|| paragraph_direction(text, line.start_bp));
+            let shaped_text = shapers.with(|shaper| {
+                shape_line_local(
+                    shaper,
+                    &text_slice,
+                    &line.variations,
+                    &line.words,
+                    &line.alternates,
+                    Some(direction),
+                )
+            });
+
+            let free_space = column_width.saturating_sub(
+                shaped_text
+                    .iter()
+                    .map(|g| g.x_advance as f32 * scale_factor.horizontal)
+                    .sum::<f32>() as u32,
+            );
+
+            let rtl = direction == Direction::Rtl;
+            let line_offset = match (alignment, last_line) {
+                (Alignment::Center, _) => free_space / 2,
+                (Alignment::Justify, LastLine::Center) if !line.justified => free_space / 2,
+                (Alignment::End, _) if rtl => 0,
+                (Alignment::End, _) => free_space,
+                (Alignment::Start | Alignment::Justify, _) if rtl => free_space,
+                (Alignment::Start | Alignment::Justify, _) => 0,
+            };
+
+            let origin_x = (column_x(*position) + line_offset) as f32;
+            let origin_y = ((idx - column.start) as u32 * line_height) as f32 + ascent;
+
+            let mut caret = 0;
+            let glyphs = shaped_text
+                .into_iter()
+                .map(|glyph| {
+                    let g = Glyph {
+                        id: glyph.codepoint as u16,
+                        font: glyph.font,
+                        cluster: glyph.cluster,
+                        x: origin_x + (caret + glyph.x_offset) as f32 * scale_factor.horizontal,
+                        y: origin_y - (glyph.y_offset as f32 * scale_factor.vertical),
+                    };
+                    caret += glyph.x_advance;
+                    g
+                })
+                .collect();
+
+            Line {
+                range: line.start_bp..line.end_bp,
+                text: text_slice,
+                variations: pinned(&line.variations),
+                words: line
+                    .words
+                    .iter()
+                    .map(|(range, variations)| (range.clone(), pinned(variations)))
+                    .collect(),
+                kashidas: line.kashida_positions(),
+                hyphenated: line.hyphenated,
+                justified: line.justified,
+                glyphs,
+            }
+        })
+        .collect();
+
+    Ok(Layout {
+        lines,
+        pages,
+        columns,
+        width,
+        page_height,
+        line_height,
+        ascent,
+    })
+}
+
+/// Where the paragraphs of a parallel text go relative to the main text's.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Placement {
+    #[default]
+    Beneath,
+    /// In a column next to the main text, which is on the side its direction starts from.
+    Beside,
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "beneath" => Ok(Self::Beneath),
+            "beside" => Ok(Self::Beside),
+            _ => Err(format!("Unknown placement: {s}")),
+        }
+    }
+}
+
+/// Lays out two texts, such as a verse and its translation, so that corresponding
+/// paragraphs line up. Paragraphs are paired in order and each is broken on its own,
+/// with its text's fonts and options.
+///
+/// `options.width` and `options.gutter` are for the whole block. The layout is a single
+/// page in one column. Glyphs of the parallel text index the fonts after `fonts`, and
+/// its lines' ranges index into `parallel_text`.
+pub fn layout_parallel(
+    (text, fonts, options): (&str, &[(&[u8], u32)], LayoutOptions),
+    (parallel_text, parallel_fonts, parallel_options): (&str, &[(&[u8], u32)], LayoutOptions),
+    placement: Placement,
+) -> Result<Layout, Box<dyn Error>> {
+    let width = options.width;
+    let column_width = match placement {
+        Placement::Beneath => width,
+        Placement::Beside => width.saturating_sub(options.gutter) / 2,
+    };
+    let (main_x, parallel_x) = match placement {
+        Placement::Beneath => (0, 0),
+        Placement::Beside => match options.direction.unwrap_or_else(|| Direction::of(text)) {
+            Direction::Rtl => (width - column_width, 0),
+            Direction::Ltr => (0, width - column_width),
+        },
+    };
+
+    // each text's shapers serve all its paragraphs
+    let typesetter = Typesetter::new(text, fonts, &options)?;
+    let parallel_typesetter = Typesetter::new(parallel_text, parallel_fonts, &parallel_options)?;
+
+    let layout_paragraph =
+        |(offset, paragraph): (usize, &str), typesetter: &Typesetter, options: &LayoutOptions| {
+            if paragraph.trim().is_empty() {
+                return Ok(None);
+            }
+            let options = LayoutOptions {
+                width: column_width,
+                page_height: None,
+                columns: 1,
+                ..options.clone()
+            };
+            layout_with(typesetter, paragraph, options).map(|l| Some((offset, l)))
+        };
+
+    let mut lines = vec![];
+    let mut metrics = None;
+    let mut y = 0;
+    for pair in paragraphs(text).zip_longest(paragraphs(parallel_text)) {
+        let (main, parallel) = pair.left_and_right();
+        let main = main
+            .map(|p| layout_paragraph(p, &typesetter, &options))
+            .transpose()?
+            .flatten();
+        let parallel = parallel
+            .map(|p| layout_paragraph(p, &parallel_typesetter, &parallel_options))
+            .transpose()?
+            .flatten();
+
+        let main_height = main.as_ref().map_or(0, |(_, l)| l.height());
+        let parallel_height = parallel.as_ref().map_or(0, |(_, l)| l.height());
+        let parallel_y = match placement {
+            Placement::Beneath => y + main_height,
+            Placement::Beside => y,
+        };
+
+        if let Some((offset, main)) = main {
+            metrics.get_or_insert((main.line_height, main.ascent));
+            lines.extend(shift_lines(main, offset, (main_x, y), 0));
+        }
+        if let Some((offset, parallel)) = parallel {
+            lines.extend(shift_lines(
+                parallel,
+                offset,
+                (parallel_x, parallel_y),
+                fonts.len(),
+            ));
+        }
+
+        y = match placement {
+            Placement::Beneath => parallel_y + parallel_height,
+            Placement::Beside => y + main_height.max(parallel_height),
+        };
+    }
+
+    let (line_height, ascent) = metrics.unwrap_or_default();
+    let all_lines = std::iter::once(0..lines.len());
+    Ok(Layout {
+        pages: all_lines.clone().collect(),
+        columns: all_lines.collect(),
+        lines,
+        width,
+        page_height: Some(y),
+        line_height,
+        ascent,
+    })
+}
+
+/// Paragraphs of `text` with their byte offsets.
+fn paragraphs(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split("\n\n")
+        .map(move |p| (p.as_ptr() as usize - text.as_ptr() as usize, p))
+}
+
+/// Moves a layout's lines to `(x, y)`, and offsets their ranges and fonts.
+fn shift_lines(
+    layout: Layout,
+    text_offset: usize,
+    (x, y): (u32, u32),
+    font_offset: usize,
+) -> impl Iterator<Item = Line> {
+    layout.lines.into_iter().map(move |mut line| {
+        line.range = line.range.start + text_offset..line.range.end + text_offset;
+        for glyph in &mut line.glyphs {
+            glyph.x += x as f32;
+            glyph.y += y as f32;
+            glyph.font += font_offset;
+        }
+        line
+    })
+}
+
+/// Base direction of the paragraph containing `bp`. Paragraphs without strongly
+/// directional characters are left to right.
+fn paragraph_direction(
+    text: &str,
+    bp: usize,
+) -> Direction {
+    let start = text[..bp].rfind("\n\n").map_or(0, |p| p + 2);
+    let end = text[bp..].find("\n\n").map_or(text.len(), |p| bp + p);
+
+    Direction::of(&text[start..end])
+}
+
+/// Splits lines into pages of at most `per_page` lines. `starts` marks the lines that
+/// begin a paragraph.
+///
+/// A page does not end with the first line of a paragraph, nor does the next one start
+/// with its last line, unless the page would be left with a single line.
+fn paginate(
+    starts: &[bool],
+    per_page: usize,
+) -> Vec<Range<usize>> {
+    let count = starts.len();
+    let mut pages = vec![];
+
+    let mut start = 0;
+    while start < count {
+        let mut end = (start + per_page.max(1)).min(count);
+
+        while end < count && end - start > 1 {
+            let orphan = starts[end - 1] && !starts[end];
+            let widow = !starts[end] && starts.get(end + 1).is_none_or(|&s| s);
+            if !orphan && !widow {
+                break;
+            }
+            end -= 1;
+        }
+
+        pages.push(start..end);
+        start = end;
+    }
+
+    pages
+}
+
+/// Splits each of `pages` into at most `column_count` columns of at most `per_column`
+/// lines, balanced to end at similar heights. Full pages are balanced by construction.
+///
+/// Columns are broken like pages, where the page has room for it. Otherwise the lines
+/// are split evenly, even if that leaves a paragraph's first or last line on its own.
+fn balance_columns(
+    starts: &[bool],
+    pages: &[Range<usize>],
+    (column_count, per_column): (usize, usize),
+) -> Vec<Range<usize>> {
+    pages
+        .iter()
+        .flat_map(|page| {
+            let balanced = page.len().div_ceil(column_count).max(1);
+            let frames = (balanced..=per_column.max(balanced))
+                .map(|per_column| paginate(&starts[page.clone()], per_column))
+                .find(|frames| frames.len() <= column_count)
+                .unwrap_or_else(|| {
+                    (0..page.len())
+                        .step_by(balanced)
+                        .map(|s| s..(s + balanced).min(page.len()))
+                        .collect()
+                });
+            frames
+                .into_iter()
+                .map(|f| page.start + f.start..page.start + f.end)
+        })
+        .collect()
+}
+
+/// Horizontal position of the column at `position` on its page. The first column is
+/// on the right in right to left text.
+fn column_x(
+    position: usize,
+    (width, column_width, gutter): (u32, u32, u32),
+    rtl: bool,
+) -> u32 {
+    let x = position as u32 * (column_width + gutter);
+    if rtl {
+        width.saturating_sub(x + column_width)
+    } else {
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAQQ: &[u8] = include_bytes!("../fonts/Raqq.ttf");
+    const GEORAMA: &[u8] = include_bytes!("../fonts/Georama-VariableFont_wdth,wght.ttf");
+
+    #[test]
+    fn lays_out_with_default_options() {
+        let text = "بسم الله الرحمن الرحيم الحمد لله رب العالمين";
+        let options = LayoutOptions {
+            last_line: LastLine::Start,
+            ..LayoutOptions::new(600, 40.0)
+        };
+        let layout = layout(text, &[(RAQQ, 0)], options).unwrap();
+
+        assert!(layout.lines.len() > 1);
+        assert_eq!(layout.pages.len(), 1);
+        assert!(layout.lines.iter().all(|l| !l.glyphs.is_empty()));
+    }
+
+    /// Marks the first line of paragraphs of the given lengths.
+    fn starts(paragraphs: &[usize]) -> Vec<bool> {
+        paragraphs
+            .iter()
+            .flat_map(|&len| (0..len).map(|i| i == 0))
+            .collect()
+    }
+
+    #[test]
+    fn pages_hold_at_most_their_lines() {
+        assert_eq!(paginate(&starts(&[10]), 4), [0..4, 4..8, 8..10]);
+        assert_eq!(paginate(&starts(&[10]), 10), [Range { start: 0, end: 10 }]);
+        assert_eq!(paginate(&starts(&[]), 4), []);
+        // a page too short for a line still takes one
+        assert_eq!(paginate(&starts(&[2]), 0), [0..1, 1..2]);
+    }
+
+    #[test]
+    fn first_lines_move_to_the_next_page() {
+        assert_eq!(paginate(&starts(&[3, 3]), 4), [0..3, 3..6]);
+    }
+
+    #[test]
+    fn last_lines_take_a_line_with_them() {
+        assert_eq!(paginate(&starts(&[5, 2]), 4), [0..3, 3..7]);
+    }
+
+    #[test]
+    fn single_lines_are_kept_when_nothing_else_fits() {
+        assert_eq!(paginate(&starts(&[1, 1, 1]), 1), [0..1, 1..2, 2..3]);
+        assert_eq!(paginate(&starts(&[3]), 2), [0..1, 1..3]);
+    }
+
+    /// A single page of `lines` lines.
+    fn whole(lines: usize) -> [Range<usize>; 1] {
+        [Range {
+            start: 0,
+            end: lines,
+        }]
+    }
+
+    #[test]
+    fn columns_end_at_similar_heights() {
+        let starts = [
+            true, false, false, false, false, true, false, false, false, false,
+        ];
+        assert_eq!(balance_columns(&starts, &whole(10), (2, 10)), [0..5, 5..10]);
+        assert_eq!(
+            balance_columns(&starts[..9], &whole(9), (2, 10)),
+            [0..5, 5..9]
+        );
+        // the last line of the first paragraph moves along with the one before
+        assert_eq!(
+            balance_columns(&starts, &whole(10), (3, 10)),
+            [0..3, 3..7, 7..10]
+        );
+        // each page is balanced on its own
+        let starts = [true; 6];
+        assert_eq!(
+            balance_columns(&starts, &[0..2, 2..6], (2, 2)),
+            [0..1, 1..2, 2..4, 4..6]
+        );
+    }
+
+    #[test]
+    fn columns_keep_paragraph_ends_together_where_they_fit() {
+        // a line on its own, then a paragraph of three
+        let starts = [true, true, false, false];
+        // balanced columns of two would leave a line of the paragraph on its own
+        assert_eq!(balance_columns(&starts, &whole(4), (2, 4)), [0..1, 1..4]);
+        // unless the page has no room for a longer column
+        assert_eq!(balance_columns(&starts, &whole(4), (2, 2)), [0..2, 2..4]);
+    }
+
+    #[test]
+    fn right_to_left_columns_start_on_the_right() {
+        let frame = (1000, 450, 100);
+        assert_eq!(column_x(0, frame, false), 0);
+        assert_eq!(column_x(1, frame, false), 550);
+        assert_eq!(column_x(0, frame, true), 550);
+        assert_eq!(column_x(1, frame, true), 0);
+    }
+
+    #[test]
+    fn parallel_paragraphs_line_up() {
+        let paragraphs = ["بسم الله الرحمن الرحيم", "الحمد لله رب العالمين"];
+        let translations = [
+            "In the name of God, the Most Gracious, the Most Merciful",
+            "Praise be to God, Lord of the worlds",
+        ];
+        let (text, parallel_text) = (paragraphs.join("\n\n"), translations.join("\n\n"));
+        let (fonts, parallel_fonts): (&[_], &[_]) = (&[(RAQQ, 0)], &[(GEORAMA, 0)]);
+        let options = |font_size| LayoutOptions {
+            last_line: LastLine::Start,
+            ..LayoutOptions::new(1200, font_size)
+        };
+
+        for placement in [Placement::Beside, Placement::Beneath] {
+            let column_width = match placement {
+                Placement::Beside => 600,
+                Placement::Beneath => 1200,
+            };
+            let laid = layout_parallel(
+                (&text, fonts, options(40.0)),
+                (&parallel_text, parallel_fonts, options(20.0)),
+                placement,
+            )
+            .unwrap();
+
+            // each paragraph as it is laid out on its own, at the top of the page
+            let alone = |paragraph, fonts, font_size| {
+                let options = LayoutOptions {
+                    width: column_width,
+                    ..options(font_size)
+                };
+                layout(paragraph, fonts, options).unwrap()
+            };
+            let close = |a: f32, b: f32| (a - b).abs() < 0.01;
+
+            // pairs take the main paragraph's lines, then the translation's
+            let mut lines = laid.lines.iter();
+            let mut pair_y = 0.0;
+            for (paragraph, translation) in paragraphs.iter().zip(translations) {
+                let main = alone(paragraph, fonts, 40.0);
+                let parallel = alone(translation, parallel_fonts, 20.0);
+                let main_lines = lines.by_ref().take(main.lines.len()).collect::<Vec<_>>();
+                let parallel_lines = lines
+                    .by_ref()
+                    .take(parallel.lines.len())
+                    .collect::<Vec<_>>();
+
+                let y = main_lines[0].glyphs[0].y - main.lines[0].glyphs[0].y;
+                let parallel_y = parallel_lines[0].glyphs[0].y - parallel.lines[0].glyphs[0].y;
+                assert!(close(y, pair_y), "{placement:?}");
+                let expected = match placement {
+                    Placement::Beside => y,
+                    Placement::Beneath => y + main.height() as f32,
+                };
+                assert!(close(parallel_y, expected), "{placement:?}");
+                pair_y = match placement {
+                    Placement::Beside => y + main.height().max(parallel.height()) as f32,
+                    Placement::Beneath => parallel_y + parallel.height() as f32,
+                };
+
+                for (line, alone) in parallel_lines.iter().zip(&parallel.lines) {
+                    assert_eq!(line.glyphs.len(), alone.glyphs.len());
+                    for (glyph, alone) in line.glyphs.iter().zip(&alone.glyphs) {
+                        assert_eq!(glyph.font, alone.font + fonts.len());
+                    }
+                }
+            }
+            assert!(lines.next().is_none());
+        }
+    }
+}
@@ -1,6 +1,16 @@
 mod draw;
+mod fvar;
+mod layout;
 mod logic;
+mod pdf;
 mod shaper;
+mod svg;
 
-pub use draw::{run, ImageConfig};
-pub use logic::Variation;
+pub use draw::{run, ImageConfig, OutputFormat, ParallelText};
+pub use fvar::{font_axes, instance_coordinates, Axis, Coordinates};
+pub use layout::{layout, layout_parallel, Glyph, Layout, LayoutOptions, Line, Placement};
+pub use logic::{
+    Alignment, Demerits, KashidaOrder, KashidaPolicy, LastLine, ParagraphError, Variation,
+    VariationKind,
+};
+pub use shaper::{Backend, Direction};
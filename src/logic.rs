@@ -1,6 +1,11 @@
-use crate::shaper::Shaper;
-use itertools::Itertools as _;
-use std::{cmp::Ordering, ops::Not};
+use crate::shaper::{shape_line, shape_line_local, Direction, GlyphData, Shaper, ShaperPool};
+use rayon::prelude::*;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    ops::{Not, Range},
+};
+use unicode_joining_type::{get_joining_group, get_joining_type, JoiningGroup, JoiningType};
 
 #[derive(Clone, Copy, Debug)]
 pub struct Variation {
@@ -11,23 +16,51 @@ pub struct Variation {
     max: f32,
 
     best: f32,
+    /// How fast the variation moves away from `best` relative to the others
+    /// when a line is fit. Zero keeps it at `best`.
+    weight: f32,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum VariationKind {
     Axis([u8; 4]),
     Spacing,
+    /// Space added between letters, in thousandths of an em. Letters of joining
+    /// scripts such as Arabic are left connected.
+    Tracking,
 }
 
 impl Variation {
+    /// Scales the width of the space glyph. A `best` of 1.0 keeps it as is.
     #[must_use]
-    pub fn new_spacing() -> Self {
+    pub fn new_spacing(
+        min: f32,
+        max: f32,
+        best: f32,
+    ) -> Self {
         Self {
             kind: VariationKind::Spacing,
-            min: 0.25, // I dunno
-            max: 1.25, // maybe?
-            best: 1.0,
-            current_value: 1.0,
+            min,
+            max,
+            best,
+            current_value: best,
+            weight: 1.0,
+        }
+    }
+
+    #[must_use]
+    pub fn new_tracking(
+        min: f32,
+        max: f32,
+        best: f32,
+    ) -> Self {
+        Self {
+            kind: VariationKind::Tracking,
+            min,
+            max,
+            best,
+            current_value: best,
+            weight: 1.0,
         }
     }
 
@@ -44,6 +77,18 @@ impl Variation {
             max,
             best,
             current_value: best,
+            weight: 1.0,
+        }
+    }
+
+    #[must_use]
+    pub fn with_weight(
+        self,
+        weight: f32,
+    ) -> Self {
+        Self {
+            weight: weight.max(0.0),
+            ..self
         }
     }
 
@@ -57,11 +102,48 @@ impl Variation {
         dif.abs().powi(priority as i32 + 2) as usize
     }
 
-    fn change_current_val(
+    /// Where `current_value` sits between `best` and the bound it moved towards,
+    /// from -1.0 (at `min`) to 1.0 (at `max`).
+    fn stretch(&self) -> f32 {
+        let range = if self.current_value < self.best {
+            self.best - self.min
+        } else {
+            self.max - self.best
+        };
+
+        if range == 0.0 {
+            0.0
+        } else {
+            (self.current_value - self.best) / range
+        }
+    }
+
+    /// Inverse of `stretch`. Values outside -1.0..=1.0 are clamped.
+    fn set_stretch(
         &mut self,
-        new_val: f32,
+        stretch: f32,
     ) {
-        self.current_value = new_val;
+        let stretch = stretch.clamp(-1.0, 1.0);
+        self.current_value = if stretch < 0.0 {
+            self.best + stretch * (self.best - self.min)
+        } else {
+            self.best + stretch * (self.max - self.best)
+        };
+    }
+
+    /// The variation with `current_value` rounded to a step of its range, about as
+    /// fine as fonts store their coordinates. Shaping cannot tell the values of
+    /// a step apart, so they can share shaping results.
+    pub(crate) fn quantized(&self) -> Self {
+        let step = (self.max - self.min) / 16384.0;
+        if step == 0.0 {
+            return *self;
+        }
+        let steps = ((self.current_value - self.min) / step).round();
+        Self {
+            current_value: self.min + steps * step,
+            ..*self
+        }
     }
 }
 
@@ -70,42 +152,192 @@ pub struct LineData {
     pub start_bp: usize,
     pub end_bp: usize,
     pub variations: Vec<Variation>,
-    pub kashida_count: usize,
+    /// Byte positions in the line's slice where tatweels are inserted, repeated for
+    /// several at the same place.
+    pub kashidas: Vec<usize>,
+    /// The line ends inside a word, with a hyphen added.
+    pub hyphenated: bool,
+    /// The line was fit to the goal width. Otherwise it is at rest values.
+    pub justified: bool,
+    /// Part of the goal width the line leaves empty when it is not justified,
+    /// from 0.0 to 1.0.
+    pub slack: f32,
+    /// Variation values of each word, with byte ranges into `LineData::text`, when
+    /// words are varied on their own. `variations` then applies to the spaces.
+    pub words: Vec<(Range<usize>, Vec<Variation>)>,
+    /// Byte positions in `LineData::text` of the characters set with the shaper's
+    /// alternates feature to widen the line.
+    pub alternates: Vec<usize>,
 }
 
 impl LineData {
     pub fn new(
         start_bp: usize,
         end_bp: usize,
-        variations: &Vec<Variation>,
-        kashida_count: usize,
+        variations: &[Variation],
+        kashidas: &[usize],
+        hyphenated: bool,
     ) -> Self {
         Self {
             start_bp,
             end_bp,
-            variations: variations.clone(),
-            kashida_count,
+            variations: variations.to_vec(),
+            kashidas: kashidas.to_vec(),
+            hyphenated,
+            justified: true,
+            slack: 0.0,
+            words: vec![],
+            alternates: vec![],
         }
     }
 
+    /// The text to be shaped for this line, with its hyphen and kashidas.
+    pub(crate) fn text(
+        &self,
+        full_text: &str,
+    ) -> String {
+        let t = line_slice(full_text, (self.start_bp, self.end_bp, self.hyphenated));
+        kashida::place_kashidas(&t, &self.kashidas, self.kashidas.len()).into_owned()
+    }
+
+    /// Byte positions in `LineData::text` of the inserted tatweels.
+    pub(crate) fn kashida_positions(&self) -> Vec<usize> {
+        let mut locs = self.kashidas.clone();
+        locs.sort_unstable();
+        // each tatweel moves the ones after it along by its own length
+        locs.iter()
+            .enumerate()
+            .map(|(i, loc)| loc + i * 'ـ'.len_utf8())
+            .collect()
+    }
+
     pub(crate) fn cost(&self) -> usize {
-        let k_v = Variation {
-            kind: VariationKind::Spacing,
-            current_value: self.kashida_count as f32,
-            min: 0.0,
-            max: 100.0,
-            best: 0.0,
-        }
-        .cost(self.variations.len());
+        // kashidas and alternates are counted like a variation from 0 to 100
+        let count = |n: usize| {
+            Variation {
+                kind: VariationKind::Spacing,
+                current_value: n as f32,
+                min: 0.0,
+                max: 100.0,
+                best: 0.0,
+                weight: 0.0,
+            }
+            .cost(self.variations.len())
+        };
+        let k_v = count(self.kashidas.len()) + count(self.alternates.len());
+
+        // words varied past the line's values, once its favored words reached their bounds
+        let words = self
+            .words
+            .iter()
+            .flat_map(|(_, word)| word.iter().zip(&self.variations).enumerate())
+            .filter(|(_, (w, l))| w.current_value != l.current_value)
+            .map(|(i, (w, _))| w.cost(i))
+            .sum::<usize>();
 
         self.variations
             .iter()
             .enumerate()
-            .fold(k_v, |acc, (i, v)| acc + v.cost(i))
+            .fold(k_v + words, |acc, (i, v)| acc + v.cost(i))
+    }
+
+    /// Cost of the space an unjustified line leaves empty, counted like a variation
+    /// at `slack` percent of its range, so ragged lines come out at similar lengths.
+    fn raggedness(&self) -> usize {
+        (self.slack * 100.0).powi(2) as usize
+    }
+
+    fn fitness(&self) -> Fitness {
+        if !self.kashidas.is_empty() {
+            return Fitness::VeryLoose;
+        }
+
+        let stretch = self.variations.iter().map(Variation::stretch).sum::<f32>()
+            / self.variations.len() as f32;
+
+        match stretch {
+            s if s < -0.5 => Fitness::Tight,
+            s if s <= 0.5 => Fitness::Decent,
+            _ => Fitness::Loose,
+        }
     }
 }
 
-#[derive(Debug)]
+/// TeX's fitness classes, ordered from tightest to loosest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Fitness {
+    Tight,
+    Decent,
+    Loose,
+    VeryLoose,
+}
+
+/// Paragraph level demerits, added on top of each line's own cost when choosing
+/// where to break a paragraph.
+#[derive(Clone, Copy, Debug)]
+pub struct Demerits {
+    /// Added for every line, so fewer lines are preferred.
+    pub line_penalty: usize,
+    /// Added when adjacent lines are more than one fitness class apart.
+    /// (e.g. a tight line followed by a loose one)
+    pub fitness_mismatch: usize,
+    /// Multiplies the squared difference, in percent of each axis' range,
+    /// between the variation values of adjacent lines.
+    pub variation_difference: usize,
+    /// Added for every line ending with a hyphen.
+    pub hyphen_penalty: usize,
+    /// Added when two adjacent lines both end with a hyphen.
+    pub consecutive_hyphens: usize,
+}
+
+impl Default for Demerits {
+    fn default() -> Self {
+        Self {
+            line_penalty: 10,
+            fitness_mismatch: 10_000,
+            variation_difference: 1,
+            hyphen_penalty: 50,
+            consecutive_hyphens: 10_000,
+        }
+    }
+}
+
+impl Demerits {
+    fn between(
+        &self,
+        previous: &LineData,
+        next: &LineData,
+    ) -> usize {
+        let fitness = match (previous.fitness() as u8).abs_diff(next.fitness() as u8) {
+            0 | 1 => 0,
+            _ => self.fitness_mismatch,
+        };
+
+        let variation = previous
+            .variations
+            .iter()
+            .zip(&next.variations)
+            .map(|(p, n)| {
+                let dif = (p.current_value - n.current_value) * 100.0 / (p.max - p.min);
+                if dif.is_finite() {
+                    dif.powi(2) as usize
+                } else {
+                    0
+                }
+            })
+            .sum::<usize>();
+
+        let hyphens = if previous.hyphenated && next.hyphenated {
+            self.consecutive_hyphens
+        } else {
+            0
+        };
+
+        fitness + variation * self.variation_difference + hyphens
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 enum LineErrorKind {
     TooLoose,
     TooTight,
@@ -118,19 +350,19 @@ use LineErrorKind::{TooLoose, TooTight};
 struct LineError {
     kind: LineErrorKind,
     variations: Vec<Variation>,
-    kashida_count: usize,
+    kashidas: Vec<usize>,
 }
 
 impl LineError {
     fn new(
         kind: LineErrorKind,
         variations: Vec<Variation>,
-        kashida_count: usize,
+        kashidas: &[usize],
     ) -> Self {
         Self {
             kind,
             variations,
-            kashida_count,
+            kashidas: kashidas.to_vec(),
         }
     }
 }
@@ -149,51 +381,148 @@ impl std::fmt::Display for LineError {
     }
 }
 
-fn find_optimal_line_1_axis<'a>(
+fn line_slice(
+    full_text: &str,
+    (start_bp, end_bp, hyphenated): (usize, usize, bool),
+) -> Cow<'_, str> {
+    let t = full_text[start_bp..end_bp].trim();
+    if hyphenated {
+        Cow::Owned(format!("{t}-"))
+    } else {
+        Cow::Borrowed(t)
+    }
+}
+
+/// Fits the line by moving all variations at once. Each moves away from its
+/// best value at a rate proportional to its weight, so heavier variations take
+/// more of the adjustment and reach their bounds first, after which the lighter
+/// ones carry on alone. Only the weights decide how the change is split: the
+/// order of the variations feeds into the line's demerits, not into the fit.
+///
+/// With `local` set, the stretching is concentrated on some words: those with
+/// extensible letters, or the longest word if there are none. The other words
+/// only start moving once these reach their bounds.
+#[allow(clippy::too_many_arguments)]
+fn find_optimal_line_joint<'a>(
     shaper: &mut impl Shaper<'a>,
     text: &str,
-    (start_bp, end_bp): (usize, usize),
+    (start_bp, end_bp, hyphenated): (usize, usize, bool),
     goal_width: u32,
-    vv_idx: usize,
     mut variations: Vec<Variation>,
-    (kashida_locs, kashida_count): (&[usize], usize),
+    kashidas: &[usize],
+    alternates: &[usize],
+    BreakOptions {
+        local, direction, ..
+    }: &BreakOptions,
 ) -> Result<LineData, LineError> {
-    assert!(
-        vv_idx < variations.len(),
-        "Index should be within the variations array"
-    );
+    let ret = LineData {
+        alternates: alternates.to_vec(),
+        ..LineData::new(start_bp, end_bp, &variations, kashidas, hyphenated)
+    };
 
-    let ret = LineData::new(start_bp, end_bp, &variations, kashida_count);
+    // every variation is at its bound at either end of the range
+    let reach = variations
+        .iter()
+        .map(|v| v.weight)
+        .filter(|&w| w > 0.0)
+        .min_by(f32::total_cmp)
+        .map_or(0.0, |w| 1.0 / w);
+    let mut search_range = if *local {
+        -2.0 * reach..2.0 * reach
+    } else {
+        -reach..reach
+    };
 
-    let mut search_range = variations[vv_idx].min..variations[vv_idx].max;
+    let line_slice = line_slice(text, (start_bp, end_bp, hyphenated));
+    let text_slice = // if kashidas is empty this is a noop.
+        kashida::place_kashidas(&line_slice, kashidas, kashidas.len());
 
-    let text_slice = // if kashida_locs is empty this is a noop.
-        kashida::place_kashidas(text[start_bp..end_bp].trim(), kashida_locs, kashida_count);
+    let words = if *local {
+        word_ranges(&text_slice)
+    } else {
+        vec![]
+    };
+    let mut favored = words
+        .iter()
+        .map(|w| {
+            kashida::find_kashidas(&text_slice[w.clone()], kashida::Script::Arabic)
+                .is_empty()
+                .not()
+        })
+        .collect::<Vec<_>>();
+    if favored.iter().all(|f| !f) {
+        if let Some((longest, _)) = words.iter().enumerate().max_by_key(|(_, w)| w.len()) {
+            favored[longest] = true;
+        }
+    }
+    let mut word_variations = vec![variations.clone(); words.len()];
 
-    let mut set_slice_to_axis_value = |val: f32| {
-        variations[vv_idx].change_current_val(val);
+    let mut set_slice_to_stretch = |t: f32| {
+        for v in &mut variations {
+            v.set_stretch(t * v.weight);
+        }
+        for (word, &favored) in word_variations.iter_mut().zip(&favored) {
+            let t = if favored {
+                t
+            } else {
+                t.signum() * (t.abs() - reach).max(0.0)
+            };
+            for v in word {
+                v.set_stretch(t * v.weight);
+            }
+        }
+        let spans = words
+            .iter()
+            .cloned()
+            .zip(word_variations.iter().cloned())
+            .collect::<Vec<_>>();
 
-        let shaped_text = shaper.shape_text(&text_slice, &variations);
+        let shaped_text = shape_line_local(
+            shaper,
+            &text_slice,
+            &variations,
+            &spans,
+            alternates,
+            *direction,
+        );
 
         let width = shaped_text.iter().map(|g| g.x_advance).sum::<i32>() as u32;
 
-        if (goal_width.saturating_sub(5)..goal_width.saturating_add(5)).contains(&width) {
-            Ordering::Equal
-        } else {
-            width.cmp(&goal_width)
-        }
+        let ordering =
+            if (goal_width.saturating_sub(5)..goal_width.saturating_add(5)).contains(&width) {
+                Ordering::Equal
+            } else {
+                width.cmp(&goal_width)
+            };
+        (ordering, variations.clone(), spans)
     };
 
-    match set_slice_to_axis_value(search_range.start) {
-        Ordering::Greater => return Err(LineError::new(TooTight, variations, kashida_count)),
-        Ordering::Equal => return Ok(LineData { variations, ..ret }),
-        Ordering::Less => (),
+    match set_slice_to_stretch(search_range.start) {
+        (Ordering::Greater, variations, _) => {
+            return Err(LineError::new(TooTight, variations, kashidas))
+        }
+        (Ordering::Equal, variations, words) => {
+            return Ok(LineData {
+                variations,
+                words,
+                ..ret
+            })
+        }
+        (Ordering::Less, ..) => (),
     }
 
-    match set_slice_to_axis_value(search_range.end) {
-        Ordering::Less => return Err(LineError::new(TooLoose, variations, kashida_count)),
-        Ordering::Equal => return Ok(LineData { variations, ..ret }),
-        Ordering::Greater => (),
+    match set_slice_to_stretch(search_range.end) {
+        (Ordering::Less, variations, _) => {
+            return Err(LineError::new(TooLoose, variations, kashidas))
+        }
+        (Ordering::Equal, variations, words) => {
+            return Ok(LineData {
+                variations,
+                words,
+                ..ret
+            })
+        }
+        (Ordering::Greater, ..) => (),
     }
 
     // What to do if variations do not change the line's width?
@@ -203,13 +532,24 @@ fn find_optimal_line_1_axis<'a>(
     loop {
         let mid = (search_range.start + search_range.end) / 2.0;
 
+        let (ordering, variations, words) = set_slice_to_stretch(mid);
         if i >= 30 {
-            return Ok(LineData { variations, ..ret });
+            return Ok(LineData {
+                variations,
+                words,
+                ..ret
+            });
         }
 
-        search_range = match set_slice_to_axis_value(mid) {
+        search_range = match ordering {
             Ordering::Less => mid..search_range.end,
-            Ordering::Equal => return Ok(LineData { variations, ..ret }),
+            Ordering::Equal => {
+                return Ok(LineData {
+                    variations,
+                    words,
+                    ..ret
+                })
+            }
             Ordering::Greater => search_range.start..mid,
         };
 
@@ -217,56 +557,476 @@ fn find_optimal_line_1_axis<'a>(
     }
 }
 
+/// Byte positions of the characters in `text` that the shaper's alternates make wider,
+/// in the order they are used: one letter from each word, last word first, before
+/// taking a second letter from any.
+fn alternate_candidates<'a>(
+    shaper: &mut impl Shaper<'a>,
+    text: &str,
+    variations: &[Variation],
+    direction: Option<Direction>,
+) -> Vec<usize> {
+    let everywhere = text.char_indices().map(|(idx, _)| idx).collect::<Vec<_>>();
+    let advances = |glyphs: Vec<GlyphData>| {
+        let mut advances = hashbrown::HashMap::<u32, i32>::new();
+        for g in glyphs {
+            *advances.entry(g.cluster).or_default() += g.x_advance;
+        }
+        advances
+    };
+    let plain = advances(shape_line_local(
+        shaper,
+        text,
+        variations,
+        &[],
+        &[],
+        direction,
+    ));
+    let wide = advances(shape_line_local(
+        shaper,
+        text,
+        variations,
+        &[],
+        &everywhere,
+        direction,
+    ));
+
+    let mut words = word_ranges(text)
+        .into_iter()
+        .rev()
+        .map(|word| {
+            let mut wider = everywhere
+                .iter()
+                .copied()
+                .filter(|idx| word.contains(idx))
+                .filter(|&idx| {
+                    let cluster = idx as u32;
+                    wide.get(&cluster).unwrap_or(&0) > plain.get(&cluster).unwrap_or(&0)
+                })
+                .collect::<Vec<_>>();
+            wider.reverse();
+            wider
+        })
+        .collect::<Vec<_>>();
+
+    let mut candidates = vec![];
+    while words.iter().any(|w| !w.is_empty()) {
+        candidates.extend(words.iter_mut().filter_map(Vec::pop));
+    }
+    candidates
+}
+
+/// Byte positions in `segment`, the text between two break points, where its leading
+/// word can be hyphenated. The word ends before the first character that is not a
+/// letter, such as punctuation or a space.
+fn hyphen_points(
+    segment: &str,
+    lang: hypher::Lang,
+) -> Vec<usize> {
+    let word = &segment[..segment
+        .find(|c: char| !c.is_alphabetic())
+        .unwrap_or(segment.len())];
+
+    // the last syllable ends at the word's end, which is not a hyphen
+    let mut bp = 0;
+    let mut points = vec![];
+    for syllable in hypher::hyphenate(word, lang) {
+        bp += syllable.len();
+        if bp < word.len() {
+            points.push(bp);
+        }
+    }
+    points
+}
+
+/// Byte ranges of the whitespace separated words in `text`.
+fn word_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut words = vec![];
+    let mut start = None;
+    for (idx, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                words.push(s..idx);
+                start = None;
+            }
+            (false, None) => start = Some(idx),
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        words.push(s..text.len());
+    }
+    words
+}
+
+/// Fits the line to `goal_width`, adding the kashidas of `options.kashida` if
+/// `kashida` is set.
 fn find_optimal_line<'a>(
     shaper: &mut impl Shaper<'a>,
     full_text: &str,
-    (start_bp, end_bp): (usize, usize),
+    (start_bp, end_bp, hyphenated): (usize, usize, bool),
     goal_width: u32,
-    variations: Vec<Variation>,
+    options: &BreakOptions,
     kashida: bool,
 ) -> Result<LineData, LineError> {
-    assert!(variations.len() > 0);
+    let variations = &options.variations;
+    assert!(!variations.is_empty());
 
-    let mut inner = |k| {
-        let mut variations = variations.clone();
-        for (idx, counter) in (0..variations.len()).rev().enumerate() {
-            let attempt = find_optimal_line_1_axis(
+    let mut inner = |kashidas: &[usize]| {
+        let fit = |shaper: &mut _, alternates: &[usize]| {
+            find_optimal_line_joint(
                 shaper,
                 full_text,
-                (start_bp, end_bp),
+                (start_bp, end_bp, hyphenated),
                 goal_width,
-                idx,
-                variations,
-                k,
-            );
+                variations.clone(),
+                kashidas,
+                alternates,
+                options,
+            )
+        };
 
-            variations = match (attempt, counter) {
-                (result @ Ok(_), _)
-                | (result @ Err(LineError { kind: TooTight, .. }), _)
-                | (result @ Err(_), 0) => return result,
-                (Err(LineError { variations, .. }), _) => variations,
-            };
+        let result = fit(&mut *shaper, &[]);
+        if !options.alternates || !matches!(result, Err(LineError { kind: TooLoose, .. })) {
+            return result;
         }
 
-        unreachable!("Inner optimal line loop always runs");
+        // too short even when fully stretched. widen letters one at a time
+        let line = line_slice(full_text, (start_bp, end_bp, hyphenated));
+        let text = kashida::place_kashidas(&line, kashidas, kashidas.len());
+        let candidates = alternate_candidates(shaper, &text, variations, options.direction);
+        let too_loose = |r: &Result<_, _>| matches!(r, Err(LineError { kind: TooLoose, .. }));
+        let mut result = fit(&mut *shaper, &candidates);
+        if too_loose(&result) {
+            return result;
+        }
+        // the line only gets wider with more alternates, so bisect on how many
+        // to find the fewest that fill it
+        let (mut loose, mut filled) = (0, candidates.len());
+        while filled - loose > 1 {
+            let n = (loose + filled) / 2;
+            let attempt = fit(&mut *shaper, &candidates[..n]);
+            if too_loose(&attempt) {
+                loose = n;
+            } else {
+                (filled, result) = (n, attempt);
+            }
+        }
+        result
     };
 
-    if kashida {
-        let locs =
-            kashida::find_kashidas(full_text[start_bp..end_bp].trim(), kashida::Script::Arabic);
-        for (k, counter) in (0..=locs.len() * 20).rev().enumerate() {
-            match (inner((&locs, k)), counter) {
-                (result @ Ok(_), _)
-                | (result @ Err(LineError { kind: TooTight, .. }), _)
-                | (result @ Err(_), 0) => return result,
-                (Err(_), _) => (),
+    if !kashida {
+        return inner(&[]);
+    }
+    let policy = &options.kashida;
+
+    let locs = policy.locations(&line_slice(full_text, (start_bp, end_bp, hyphenated)));
+
+    // Adding kashidas only widens the line. When they come after variations the
+    // fewest that fit are used, and when they come before the most that fit.
+    let (counts, done) = match policy.order {
+        KashidaOrder::After => ((0..=locs.len()).collect::<Vec<_>>(), TooTight),
+        KashidaOrder::Before => ((0..=locs.len()).rev().collect(), TooLoose),
+    };
+    let last = counts.len() - 1;
+    for (i, k) in counts.into_iter().enumerate() {
+        match inner(&locs[..k]) {
+            result @ Ok(_) => return result,
+            Err(e) if e.kind == done || i == last => return Err(e),
+            Err(_) => (),
+        }
+    }
+
+    unreachable!()
+}
+
+/// Whether kashidas are added before or after the variations are used up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KashidaOrder {
+    /// Fit lines with variations, and only add kashidas to lines that cannot be fit
+    /// otherwise, in paragraphs that cannot be broken without them.
+    #[default]
+    After,
+    /// Add as many kashidas as fit, and fit the rest with variations.
+    Before,
+}
+
+impl std::str::FromStr for KashidaOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "after" => Ok(Self::After),
+            "before" => Ok(Self::Before),
+            _ => Err(format!("Unknown kashida order: {s}")),
+        }
+    }
+}
+
+/// Where kashidas may go in a line, and how many.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KashidaPolicy {
+    /// Most kashidas in a single word.
+    pub max_per_word: usize,
+    /// Most kashidas in a line. Only limited by `max_per_word` if `None`.
+    pub max_per_line: Option<usize>,
+    /// Letters kashidas are preferably placed after, best first. Each stands for its
+    /// joining group, so `س` also covers `ش`. Other joins follow, the one the `kashida`
+    /// crate picks for the word first.
+    pub priority: Vec<char>,
+    /// Keep the line's last word free of kashidas.
+    pub avoid_last_word: bool,
+    pub order: KashidaOrder,
+}
+
+impl Default for KashidaPolicy {
+    fn default() -> Self {
+        Self {
+            max_per_word: 20,
+            max_per_line: None,
+            priority: vec![],
+            avoid_last_word: false,
+            order: KashidaOrder::After,
+        }
+    }
+}
+
+impl KashidaPolicy {
+    /// Byte positions in `line` to insert kashidas at, in the order they are added.
+    /// Words take turns: each gives its best join by `priority`, then its next best,
+    /// and around again up to `max_per_word`.
+    fn locations(
+        &self,
+        line: &str,
+    ) -> Vec<usize> {
+        // the `kashida` crate picks one join per word. among equals it goes first
+        let picks = kashida::find_kashidas(line, kashida::Script::Arabic);
+
+        let mut words = word_ranges(line);
+        if self.avoid_last_word {
+            words.pop();
+        }
+
+        let group = get_joining_group;
+        let rank = |loc: usize| {
+            let before = letters(&line[..loc]).next_back().map(|(_, c)| c);
+            self.priority
+                .iter()
+                .position(|&p| {
+                    before.is_some_and(|c| {
+                        c == p || (group(p) != JoiningGroup::NoJoiningGroup && group(p) == group(c))
+                    })
+                })
+                .unwrap_or(self.priority.len())
+        };
+
+        let mut words = words
+            .into_iter()
+            .map(|w| {
+                let mut locs = joins(&line[w.clone()])
+                    .into_iter()
+                    .map(|loc| w.start + loc)
+                    .collect::<Vec<_>>();
+                // stable, so ties stay in text order
+                locs.sort_by_key(|&loc| (rank(loc), !picks.contains(&loc)));
+                locs
+            })
+            .filter(|locs| !locs.is_empty())
+            .collect::<Vec<_>>();
+        words.sort_by_key(|locs| {
+            locs.iter()
+                .find_map(|loc| picks.iter().position(|p| p == loc))
+                .unwrap_or(picks.len())
+        });
+
+        let max = self.max_per_line.unwrap_or(usize::MAX);
+        let mut ret = vec![];
+        for turn in 0..self.max_per_word {
+            if ret.len() >= max || words.is_empty() {
+                break;
             }
+            let mut locs = words
+                .iter()
+                .map(|locs| locs[turn % locs.len()])
+                .collect::<Vec<_>>();
+            locs.sort_by_key(|&loc| rank(loc));
+            ret.extend(locs);
         }
+        ret.truncate(max);
+        ret
+    }
+}
 
-        unreachable!()
-    } else {
-        inner((&[], 0))
+/// The letters of `text` with their byte positions, skipping marks.
+fn letters(text: &str) -> impl DoubleEndedIterator<Item = (usize, char)> + '_ {
+    text.char_indices()
+        .filter(|&(_, c)| get_joining_type(c) != JoiningType::Transparent)
+}
+
+/// Byte positions in `word` where two letters join, and a kashida can go between
+/// them. Lam-alef ligatures and the name of God (لله) are left whole.
+fn joins(word: &str) -> Vec<usize> {
+    let joins_following = |c| {
+        matches!(
+            get_joining_type(c),
+            JoiningType::DualJoining | JoiningType::JoinCausing
+        )
+    };
+    let joins_preceding = |c| {
+        matches!(
+            get_joining_type(c),
+            JoiningType::DualJoining | JoiningType::JoinCausing | JoiningType::RightJoining
+        )
+    };
+    let is = |c, g| get_joining_group(c) == g;
+    let is_heh = |c| is(c, JoiningGroup::Heh) || is(c, JoiningGroup::TehMarbuta);
+
+    let letters = letters(word).collect::<Vec<_>>();
+    let mut ret = vec![];
+    for (i, pair) in letters.windows(2).enumerate() {
+        let [(_, prev), (idx, c)] = *pair else {
+            unreachable!()
+        };
+        if !joins_following(prev) || !joins_preceding(c) {
+            continue;
+        }
+        if is(prev, JoiningGroup::Lam) && is(c, JoiningGroup::Alef) {
+            continue;
+        }
+        let jalala = |at: usize| {
+            matches!(letters.get(at..at + 3), Some([(_, a), (_, b), (_, h)])
+                if is(*a, JoiningGroup::Lam) && is(*b, JoiningGroup::Lam) && is_heh(*h))
+        };
+        // `c` is the second lam or the heh of lam, lam, heh
+        if jalala(i) || i > 0 && jalala(i - 1) {
+            continue;
+        }
+        ret.push(idx);
     }
+    ret
+}
+
+/// Where lines that do not fill the goal width are placed, relative to
+/// the paragraph's direction. Only `Justify` fits lines to the goal width.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Alignment {
+    Start,
+    End,
+    Center,
+    /// Fit lines to the goal width. Lines that cannot be fit are placed at the start.
+    #[default]
+    Justify,
+}
+
+impl std::str::FromStr for Alignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "start" => Ok(Self::Start),
+            "end" => Ok(Self::End),
+            "center" => Ok(Self::Center),
+            "justify" => Ok(Self::Justify),
+            _ => Err(format!("Unknown alignment: {s}")),
+        }
+    }
+}
+
+/// How to set the last line of each paragraph when justifying.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LastLine {
+    #[default]
+    Justify,
+    /// At rest values, aligned to the start of the line.
+    Start,
+    /// At rest values, centered.
+    Center,
+    /// Justify if the line at rest values is over this fraction of the
+    /// goal width, otherwise align to the start of the line.
+    JustifyOver(f32),
+}
+
+impl std::str::FromStr for LastLine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "justify" => Ok(Self::Justify),
+            "start" => Ok(Self::Start),
+            "center" => Ok(Self::Center),
+            p => p
+                .strip_suffix('%')
+                .and_then(|p| p.trim().parse::<f32>().ok())
+                .filter(|p| (0.0..=100.0).contains(p))
+                .map(|p| Self::JustifyOver(p / 100.0))
+                .ok_or_else(|| format!("Unknown last line setting: {s}")),
+        }
+    }
+}
+
+/// Fits a line according to `fit`: either justified with `find_optimal_line`,
+/// or set at rest values when it is short enough.
+fn fit_line<'a>(
+    shaper: &mut impl Shaper<'a>,
+    full_text: &str,
+    (start_bp, end_bp, hyphenated): (usize, usize, bool),
+    goal_width: u32,
+    options: &BreakOptions,
+    kashida: bool,
+    fit: LastLine,
+) -> Result<LineData, LineError> {
+    let justify = |shaper: &mut _| {
+        find_optimal_line(
+            shaper,
+            full_text,
+            (start_bp, end_bp, hyphenated),
+            goal_width,
+            options,
+            kashida,
+        )
+    };
+
+    let threshold = match fit {
+        LastLine::Justify => return justify(shaper),
+        LastLine::Start | LastLine::Center => f32::INFINITY,
+        LastLine::JustifyOver(threshold) => threshold,
+    };
+
+    let rest = options
+        .variations
+        .iter()
+        .map(|v| Variation {
+            current_value: v.best,
+            ..*v
+        })
+        .collect::<Vec<_>>();
+
+    let width = shape_line(
+        shaper,
+        &line_slice(full_text, (start_bp, end_bp, hyphenated)),
+        &rest,
+        options.direction,
+    )
+    .iter()
+    .map(|g| g.x_advance)
+    .sum::<i32>() as u32;
+
+    if width > goal_width {
+        // has to be squeezed to fit anyway.
+        return justify(shaper);
+    }
+
+    if width as f32 >= threshold * goal_width as f32 {
+        if let result @ Ok(_) = justify(shaper) {
+            return result;
+        }
+    }
+
+    Ok(LineData {
+        justified: false,
+        slack: 1.0 - width as f32 / goal_width as f32,
+        ..LineData::new(start_bp, end_bp, &rest, &[], hyphenated)
+    })
 }
 
 #[derive(Debug)]
@@ -285,116 +1045,589 @@ impl std::fmt::Display for ParagraphError {
     }
 }
 
-pub fn line_break<'a>(
-    shaper: &mut impl Shaper<'a>,
+/// What lines are fit with and how paragraphs are broken.
+#[derive(Clone, Debug)]
+pub(crate) struct BreakOptions {
+    pub variations: Vec<Variation>,
+    pub demerits: Demerits,
+    /// Language to hyphenate words in. No hyphenation if `None`.
+    pub hyphenation: Option<hypher::Lang>,
+    pub alignment: Alignment,
+    pub last_line: LastLine,
+    pub kashida: KashidaPolicy,
+    /// Widen letters with the shaper's alternates when variations are not enough.
+    pub alternates: bool,
+    /// Vary words with extensible letters first.
+    pub local: bool,
+    /// Base direction of all paragraphs. Detected for each if `None`.
+    pub direction: Option<Direction>,
+}
+
+impl BreakOptions {
+    /// How lines are fit: the paragraph's other lines, and its last line.
+    fn fits(&self) -> (LastLine, LastLine) {
+        // unjustified text is set at rest values throughout
+        match self.alignment {
+            Alignment::Justify => (LastLine::Justify, self.last_line),
+            _ => (LastLine::Start, LastLine::Start),
+        }
+    }
+}
+
+/// Breaks each paragraph of `text` into lines, all at once, with a shaper from
+/// `shapers` for each thread.
+pub(crate) fn line_break<'a, S: Shaper<'a> + Send>(
+    shapers: &ShaperPool<S>,
     text: &str,
     goal_width: u32,
-    variations: Vec<Variation>,
+    options: &BreakOptions,
 ) -> Result<Vec<LineData>, ParagraphError> {
-    let mut paragraphs = vec![];
+    let (first, second) = match options.kashida.order {
+        KashidaOrder::After => (false, true),
+        KashidaOrder::Before => (true, false),
+    };
 
-    for paragraph in text.split("\n\n") {
-        let line_data = if let Ok(line_data) =
-            paragraph_line_break(shaper, text, paragraph, goal_width, &variations, false)
-        {
-            line_data
-        } else {
-            paragraph_line_break(shaper, text, paragraph, goal_width, &variations, true)?
-        };
-        paragraphs.extend(line_data);
-    }
+    let paragraphs = text
+        .split("\n\n")
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .map(|paragraph| {
+            // lines are measured in the order their runs are drawn in
+            let options = &BreakOptions {
+                direction: Some(
+                    options
+                        .direction
+                        .unwrap_or_else(|| Direction::of(paragraph)),
+                ),
+                ..options.clone()
+            };
+            paragraph_line_break(shapers, text, paragraph, goal_width, options, first).or_else(
+                |_| paragraph_line_break(shapers, text, paragraph, goal_width, options, second),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-    Ok(paragraphs)
+    Ok(paragraphs.into_iter().flatten().collect())
 }
 
-fn paragraph_line_break<'a>(
-    shaper: &mut impl Shaper<'a>,
+/// Breaks `paragraph`, a slice of `full_text`, into lines. Kashidas are only added
+/// to lines with more than one, if `kashida` is set.
+fn paragraph_line_break<'a, S: Shaper<'a> + Send>(
+    shapers: &ShaperPool<S>,
     full_text: &str,
     paragraph: &str,
     goal_width: u32,
-    variations: &Vec<Variation>,
+    options: &BreakOptions,
     kashida: bool,
 ) -> Result<Vec<LineData>, ParagraphError> {
+    let (line_fit, last_line) = options.fits();
     let start_bp = paragraph.as_ptr() as usize - full_text.as_ptr() as usize;
     let end_bp = start_bp + paragraph.len();
 
     // first see if the whole paragraph fits in one line
     // for example the Basmala
-    if let Ok(l_b) = match find_optimal_line(
-        shaper,
-        full_text,
-        (start_bp, end_bp),
-        goal_width,
-        variations.clone(),
-        true,
-    ) {
+    if let Ok(l_b) = match shapers.with(|shaper| {
+        fit_line(
+            shaper,
+            full_text,
+            (start_bp, end_bp, false),
+            goal_width,
+            options,
+            true,
+            last_line,
+        )
+    }) {
         Ok(data) => Ok(data),
         Err(LineError { kind: TooTight, .. }) => Err(ParagraphError::UnableToLayout),
         Err(LineError {
             variations,
-            kashida_count,
+            kashidas,
             ..
-        }) => Ok(LineData::new(start_bp, end_bp, &variations, kashida_count)),
+        }) => Ok(LineData::new(
+            start_bp,
+            end_bp,
+            &variations,
+            &kashidas,
+            false,
+        )),
     } {
         return Ok(vec![l_b]);
     }
 
-    let bps = icu_segmenter::LineSegmenter::new_auto()
+    let mut bps = icu_segmenter::LineSegmenter::new_auto()
         .segment_str(paragraph)
-        .map(|bp| bp + start_bp)
+        .map(|bp| (bp + start_bp, false))
         .collect::<Vec<_>>();
 
-    let mut nodes = hashbrown::HashSet::new();
-    nodes.insert(start_bp);
-
-    let mut edges = hashbrown::HashMap::new();
-
-    for i in 0..bps.len() {
-        if nodes.contains(&bps[i]).not() {
-            continue;
+    if let Some(lang) = options.hyphenation {
+        let mut hyphens = vec![];
+        for w in bps.windows(2) {
+            let (s, e) = (w[0].0, w[1].0);
+            hyphens.extend(
+                hyphen_points(&full_text[s..e], lang)
+                    .into_iter()
+                    .map(|bp| (s + bp, true)),
+            );
         }
 
+        bps.extend(hyphens);
+        bps.sort_unstable();
+    }
+
+    // the lines starting at a break point, until they get too long
+    let lines_from = |shaper: &mut S, i: usize| {
+        let mut lines = vec![];
         for j in (i..bps.len()).skip(1) {
-            let start_bp = bps[i];
-            let end_bp = bps[j];
+            let start_bp = bps[i].0;
+            let (end_bp, hyphenated) = bps[j];
 
             if full_text[end_bp..].chars().next().is_some_and(|c| c == '۝') {
                 // avoid lines starting with Aya markers
                 continue;
             }
 
-            match find_optimal_line(
+            match fit_line(
                 shaper,
                 full_text,
-                (start_bp, end_bp),
+                (start_bp, end_bp, hyphenated),
                 goal_width,
-                variations.clone(),
+                options,
                 kashida,
+                if bps.last().is_some_and(|&(last, _)| last == end_bp) {
+                    last_line
+                } else {
+                    line_fit
+                },
             ) {
-                Ok(data) => {
-                    nodes.insert(end_bp);
-                    edges.insert((start_bp, end_bp), data);
-                }
+                Ok(data) => lines.push(data),
                 Err(LineError { kind: TooTight, .. }) => break,
                 _ => (),
             }
         }
+        lines
+    };
+
+    let mut nodes = hashbrown::HashSet::new();
+    nodes.insert(start_bp);
+
+    let mut edges = hashbrown::HashMap::<_, Vec<LineData>>::new();
+
+    // Lines start where earlier lines end. The break points reached by the
+    // last round of lines are searched from in parallel.
+    let mut reached = vec![start_bp];
+    while !reached.is_empty() {
+        let found = reached
+            .par_iter()
+            .map(|&start| {
+                shapers.with(|shaper| {
+                    (0..bps.len())
+                        .filter(|&i| bps[i].0 == start)
+                        .flat_map(|i| lines_from(shaper, i))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let starts = std::mem::take(&mut reached);
+        for (start, lines) in starts.into_iter().zip(found) {
+            for data in lines {
+                if nodes.insert(data.end_bp) {
+                    reached.push(data.end_bp);
+                }
+                edges.entry(start).or_default().push(data);
+            }
+        }
     }
 
+    let line = |start: usize, end: usize| {
+        edges
+            .get(&start)
+            .and_then(|lines| lines.iter().find(|ld| ld.end_bp == end))
+    };
+
+    // Nodes are a break point and where the line ending there started,
+    // as demerits depend on which two lines are adjacent.
     pathfinding::prelude::dijkstra(
-        &start_bp,
-        |&p| {
-            edges
-                .iter()
-                .filter_map(move |(&(s, e), ld)| s.eq(&p).then(|| (e, ld.cost())))
+        &(start_bp, None),
+        |&(p, previous_start)| {
+            let demerits = &options.demerits;
+            let previous = previous_start.and_then(|s| line(s, p));
+            edges.get(&p).into_iter().flatten().map(move |ld| {
+                // the last line is as long as the paragraph makes it
+                let raggedness = if ld.end_bp == end_bp {
+                    0
+                } else {
+                    ld.raggedness()
+                };
+                let cost = demerits.line_penalty
+                    + ld.cost()
+                    + raggedness
+                    + if ld.hyphenated {
+                        demerits.hyphen_penalty
+                    } else {
+                        0
+                    }
+                    + previous.map_or(0, |previous| demerits.between(previous, ld));
+                ((ld.end_bp, Some(p)), cost)
+            })
         },
-        |&p| p == end_bp,
+        |&(p, _)| p == end_bp,
     )
     .and_then(|(path, _)| {
         path.into_iter()
-            .tuple_windows()
-            .map(|key: (_, _)| edges.get(&key).cloned())
+            .filter_map(|(e, s)| Some(line(s?, e).cloned()))
             .collect::<Option<Vec<_>>>()
     })
     .ok_or(ParagraphError::UnableToLayout)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shaper::RustBuzz;
+
+    const GEORAMA: &[u8] = include_bytes!("../fonts/Georama-VariableFont_wdth,wght.ttf");
+    const NOTO_ARABIC: &[u8] = include_bytes!("../fonts/NotoArabic.ttf");
+
+    fn options() -> BreakOptions {
+        BreakOptions {
+            variations: vec![
+                Variation::new_spacing(0.25, 3.0, 1.0),
+                Variation::new_tracking(0.0, 100.0, 50.0),
+            ],
+            demerits: Demerits::default(),
+            hyphenation: None,
+            alignment: Alignment::Justify,
+            last_line: LastLine::Start,
+            kashida: KashidaPolicy::default(),
+            alternates: false,
+            local: false,
+            direction: None,
+        }
+    }
+
+    fn width(glyphs: &[GlyphData]) -> u32 {
+        glyphs.iter().map(|g| g.x_advance).sum::<i32>() as u32
+    }
+
+    #[test]
+    fn ragged_lines_are_balanced() {
+        let text = "The quick brown fox jumps over the lazy dog while five boxing wizards \
+            jump quickly and a wizard's job is to vex chumps quickly in fog";
+        let new = || RustBuzz::new(&[(GEORAMA, 0)], &[], &[]).unwrap();
+        let goal = 4000;
+        let options = BreakOptions {
+            alignment: Alignment::Start,
+            ..options()
+        };
+
+        let lines = line_break(&ShaperPool::new(new), text, goal, &options).unwrap();
+        let widths = lines
+            .iter()
+            .map(|l| width(&shape_line(&mut new(), &l.text(text), &l.variations, None)))
+            .collect::<Vec<_>>();
+        // the last line is left as long as it is
+        let full = &widths[..widths.len() - 1];
+        assert!(full.iter().all(|&w| w > goal * 3 / 4), "{widths:?}");
+    }
+
+    #[test]
+    fn lines_are_measured_in_their_paragraph_direction() {
+        let text =
+            "بسم الله abc def الرحمن الرحيم ghi jkl mno الحمد لله pqr stu رب العالمين vwx yz";
+        let new = || RustBuzz::new(&[(NOTO_ARABIC, 0), (GEORAMA, 0)], &[], &[]).unwrap();
+        let goal = 8000;
+
+        let lines = line_break(&ShaperPool::new(new), text, goal, &options()).unwrap();
+        assert!(lines.len() > 2);
+        // as they are drawn, at the paragraph's direction rather than their own
+        for line in lines.iter().filter(|l| l.justified) {
+            let glyphs = shape_line(
+                &mut new(),
+                &line.text(text),
+                &line.variations,
+                Some(Direction::Rtl),
+            );
+            assert!(width(&glyphs).abs_diff(goal) <= 5, "{:?}", line.text(text));
+        }
+    }
+
+    #[test]
+    fn every_join_is_a_kashida_location() {
+        assert_eq!(joins("بسم"), [2, 4]);
+        // marks stay on their letters
+        assert_eq!(joins("بِسْمِ"), [4, 8]);
+        // not inside lam-alef, or after alef, which does not join the next letter
+        assert_eq!(joins("سلام"), [2]);
+        assert!(joins("الله").is_empty());
+        assert!(joins("abc").is_empty());
+    }
+
+    #[test]
+    fn kashidas_follow_the_priority_within_words() {
+        let policy = |priority: &[char], max_per_word, max_per_line| KashidaPolicy {
+            max_per_word,
+            max_per_line,
+            priority: priority.to_vec(),
+            ..KashidaPolicy::default()
+        };
+
+        assert_eq!(policy(&['س'], 3, None).locations("بسم"), [4, 2, 4]);
+        assert_eq!(policy(&['ب'], 3, None).locations("بسم"), [2, 4, 2]);
+        // ش is in the joining group of س
+        assert_eq!(policy(&['س'], 2, None).locations("بشم"), [4, 2]);
+        // the crate's pick comes first among equals
+        assert_eq!(policy(&[], 2, None).locations("بسم"), [4, 2]);
+
+        assert_eq!(policy(&['ب'], 1, None).locations("بسم سلام"), [2, 9]);
+        assert_eq!(policy(&['ب'], 2, Some(1)).locations("بسم سلام"), [2]);
+        let last_word = KashidaPolicy {
+            avoid_last_word: true,
+            ..policy(&['ب'], 2, None)
+        };
+        assert_eq!(last_word.locations("بسم سلام"), [2, 4]);
+    }
+
+    #[test]
+    fn inserted_kashidas_are_found_in_the_line_text() {
+        // the tatweel after ب is in the source text
+        let text = "بـسم بسم";
+        let mut line = LineData::new(0, text.len(), &options().variations, &[6, 2, 6], false);
+        line.hyphenated = true;
+
+        let shaped = line.text(text);
+        let inserted = line.kashida_positions();
+        assert_eq!(inserted, [2, 8, 10]);
+        assert!(inserted.iter().all(|&idx| shaped[idx..].starts_with('ـ')));
+        let source = shaped
+            .char_indices()
+            .filter(|(idx, _)| !inserted.contains(idx))
+            .map(|(_, c)| c)
+            .collect::<String>();
+        assert_eq!(source, format!("{text}-"));
+    }
+
+    #[test]
+    fn demerits_follow_fitness_classes() {
+        let tracking = [Variation::new_tracking(0.0, 100.0, 50.0)];
+        let line = |stretch: f32, kashidas: &[usize], hyphenated: bool| {
+            let mut variations = tracking;
+            variations[0].set_stretch(stretch);
+            LineData::new(0, 0, &variations, kashidas, hyphenated)
+        };
+        let (tight, loose) = (line(-0.75, &[], false), line(0.75, &[], false));
+        let (decent, very_loose) = (line(0.5, &[], false), line(0.0, &[2], false));
+
+        assert_eq!(tight.fitness(), Fitness::Tight);
+        assert_eq!(line(-0.5, &[], false).fitness(), Fitness::Decent);
+        assert_eq!(decent.fitness(), Fitness::Decent);
+        assert_eq!(loose.fitness(), Fitness::Loose);
+        // kashidas make a line very loose, however it is stretched
+        assert_eq!(very_loose.fitness(), Fitness::VeryLoose);
+
+        let fitness_only = Demerits {
+            variation_difference: 0,
+            ..Demerits::default()
+        };
+        // adjacent classes are free, either way round
+        assert_eq!(fitness_only.between(&tight, &decent), 0);
+        assert_eq!(fitness_only.between(&loose, &decent), 0);
+        assert_eq!(fitness_only.between(&loose, &very_loose), 0);
+        assert_eq!(fitness_only.between(&tight, &loose), 10_000);
+        assert_eq!(fitness_only.between(&very_loose, &decent), 10_000);
+
+        let demerits = Demerits::default();
+        // 50 of the axis' range of 100 apart
+        let (rest, full) = (line(0.0, &[], false), line(1.0, &[], false));
+        assert_eq!(demerits.between(&rest, &full), 50 * 50);
+        assert_eq!(demerits.between(&full, &full), 0);
+
+        let hyphenated = line(0.0, &[], true);
+        assert_eq!(demerits.between(&hyphenated, &rest), 0);
+        assert_eq!(demerits.between(&hyphenated, &hyphenated), 10_000);
+    }
+
+    #[test]
+    fn hyphens_break_the_leading_word() {
+        let points = |segment| hyphen_points(segment, hypher::Lang::English);
+        assert_eq!(points("hyphenation"), [2, 6]);
+        // only the word is hyphenated, not what follows it
+        assert_eq!(points("hyphenation, "), [2, 6]);
+        assert_eq!(points("extraordinary-looking "), [2, 5, 7, 9]);
+        assert!(points("a").is_empty());
+        assert!(points(", ").is_empty());
+
+        let word = "hyphenation";
+        let bounds = [vec![0], points(word), vec![word.len()]].concat();
+        let syllables = bounds
+            .windows(2)
+            .map(|w| &word[w[0]..w[1]])
+            .collect::<Vec<_>>();
+        assert_eq!(syllables, ["hy", "phen", "ation"]);
+    }
+
+    #[test]
+    fn bisection_lands_on_the_goal_width() {
+        let text = "The quick brown fox jumps over the lazy dog";
+        let mut shaper = RustBuzz::new(&[(GEORAMA, 0)], &[], &[]).unwrap();
+        let line = (0, text.len(), false);
+        let variations = vec![
+            Variation::new_axis(*b"wdth", 62.5, 150.0, 100.0),
+            Variation::new_spacing(0.25, 3.0, 1.0),
+        ];
+        let at = |shaper: &mut RustBuzz, stretch: f32| {
+            let mut variations = variations.clone();
+            variations.iter_mut().for_each(|v| v.set_stretch(stretch));
+            width(&shape_line(shaper, text, &variations, None))
+        };
+        let (narrowest, widest) = (at(&mut shaper, -1.0), at(&mut shaper, 1.0));
+
+        for local in [false, true] {
+            let options = BreakOptions {
+                variations: variations.clone(),
+                local,
+                ..options()
+            };
+            let fit = |shaper: &mut RustBuzz, goal| {
+                find_optimal_line_joint(
+                    shaper,
+                    text,
+                    line,
+                    goal,
+                    options.variations.clone(),
+                    &[],
+                    &[],
+                    &options,
+                )
+            };
+
+            // widths move in steps, some wider than the tolerance, so the goals are
+            // widths the line can be set at
+            for stretch in [-0.8, -0.5, -0.2, 0.2, 0.5, 0.8] {
+                let goal = at(&mut shaper, stretch);
+                let fitted = fit(&mut shaper, goal).unwrap();
+                let glyphs = shape_line_local(
+                    &mut shaper,
+                    text,
+                    &fitted.variations,
+                    &fitted.words,
+                    &[],
+                    None,
+                );
+                assert!(width(&glyphs).abs_diff(goal) <= 5, "{goal} {local}");
+            }
+
+            let kind = |result: Result<_, LineError>| result.unwrap_err().kind;
+            assert_eq!(kind(fit(&mut shaper, narrowest - 100)), TooTight);
+            assert_eq!(kind(fit(&mut shaper, widest + 100)), TooLoose);
+        }
+    }
+
+    #[test]
+    fn last_lines_are_parsed() {
+        assert_eq!("justify".parse(), Ok(LastLine::Justify));
+        assert_eq!("Start".parse(), Ok(LastLine::Start));
+        assert_eq!("center".parse(), Ok(LastLine::Center));
+        assert_eq!("80%".parse(), Ok(LastLine::JustifyOver(0.8)));
+        assert_eq!("50 %".parse(), Ok(LastLine::JustifyOver(0.5)));
+        assert_eq!("0%".parse(), Ok(LastLine::JustifyOver(0.0)));
+        for invalid in ["end", "80", "%", "x%", "150%", "-10%", "NaN%", ""] {
+            assert!(invalid.parse::<LastLine>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn alignments_are_parsed() {
+        assert_eq!("start".parse(), Ok(Alignment::Start));
+        assert_eq!("End".parse(), Ok(Alignment::End));
+        assert_eq!("CENTER".parse(), Ok(Alignment::Center));
+        assert_eq!("justify".parse(), Ok(Alignment::Justify));
+        assert!("left".parse::<Alignment>().is_err());
+        assert!("".parse::<Alignment>().is_err());
+    }
+
+    #[test]
+    fn words_are_split_at_whitespace_only() {
+        let words = |text| {
+            word_ranges(text)
+                .into_iter()
+                .map(|w| &text[w])
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(words("بسم الله، abc def."), ["بسم", "الله،", "abc", "def."]);
+        // runs of spaces, tabs and line breaks separate words the same as one space
+        assert_eq!(words("  (abc)\t\n«الله»  "), ["(abc)", "«الله»"]);
+        // direction marks are part of the word they are in
+        assert_eq!(words("abc\u{200F}الله def"), ["abc\u{200F}الله", "def"]);
+        assert!(words("").is_empty());
+        assert!(words(" \n ").is_empty());
+    }
+
+    #[test]
+    fn alternates_widen_a_letter_of_each_word_in_turn() {
+        let mut shaper = RustBuzz::new(&[(GEORAMA, 0)], &[], &[])
+            .unwrap()
+            .with_alternates(Some(*b"smcp"));
+        let variations = &options().variations;
+
+        // small capitals are wider than most lowercase letters, and the capitals
+        // are left as they are
+        let text = "ab cde fg";
+        let candidates = alternate_candidates(&mut shaper, text, variations, None);
+        assert_eq!(candidates, [7, 3, 0, 4, 1]);
+        assert!(alternate_candidates(&mut shaper, "AB CD", variations, None).is_empty());
+
+        // positions are in the text's order, however it is drawn
+        let rtl = alternate_candidates(&mut shaper, text, variations, Some(Direction::Rtl));
+        assert_eq!(rtl, candidates);
+    }
+
+    #[test]
+    fn short_last_lines_are_left_at_rest() {
+        let text = "The quick brown fox jumps";
+        let mut shaper = RustBuzz::new(&[(GEORAMA, 0)], &[], &[]).unwrap();
+        // tracking moves the width in steps wider than the tolerance
+        let options = BreakOptions {
+            variations: vec![Variation::new_spacing(0.25, 3.0, 1.0)],
+            ..options()
+        };
+        let rest = width(&shape_line(&mut shaper, text, &options.variations, None));
+        // the line fills 90% of the goal
+        let goal = rest * 10 / 9;
+        let mut fit = |goal, fit| {
+            fit_line(
+                &mut shaper,
+                text,
+                (0, text.len(), false),
+                goal,
+                &options,
+                false,
+                fit,
+            )
+            .unwrap()
+        };
+
+        for last_line in [
+            LastLine::Start,
+            LastLine::Center,
+            LastLine::JustifyOver(0.95),
+        ] {
+            let line = fit(goal, last_line);
+            assert!(!line.justified, "{last_line:?}");
+            assert!(line.variations.iter().all(|v| v.current_value == v.best));
+            assert!((line.slack - 0.1).abs() < 0.01, "{}", line.slack);
+        }
+
+        for last_line in [LastLine::Justify, LastLine::JustifyOver(0.8)] {
+            let line = fit(goal, last_line);
+            assert!(line.justified, "{last_line:?}");
+            let mut shaper = RustBuzz::new(&[(GEORAMA, 0)], &[], &[]).unwrap();
+            let glyphs = shape_line(&mut shaper, text, &line.variations, None);
+            assert!(width(&glyphs).abs_diff(goal) <= 5, "{last_line:?}");
+        }
+
+        // lines too long for the goal are squeezed whatever the setting
+        assert!(fit(rest - 50, LastLine::Start).justified);
+    }
+}
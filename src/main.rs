@@ -10,26 +10,75 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let img_config = nun::ImageConfig {
         margin: FACTOR * config.margin,
         img_width: FACTOR * config.width,
+        page_height: config.page_height.map(|h| FACTOR * h),
+        columns: config.columns.unwrap_or(1),
+        gutter: FACTOR * config.gutter.unwrap_or(config.margin),
         font_size: FACTOR as f32 * config.font.size,
         line_height: config.font.line_height,
+        alternates: alternates(config.font.alternates.as_deref())?,
         txt_color: config.text_color.to_be_bytes(),
         bkg_color: config.bg_color.to_be_bytes(),
+        format: config.output.as_deref().unwrap_or("png").parse()?,
+        demerits: config
+            .demerits
+            .map(|d| {
+                let default = nun::Demerits::default();
+                nun::Demerits {
+                    line_penalty: d.line_penalty.unwrap_or(default.line_penalty),
+                    fitness_mismatch: d.fitness_mismatch.unwrap_or(default.fitness_mismatch),
+                    variation_difference: d
+                        .variation_difference
+                        .unwrap_or(default.variation_difference),
+                    hyphen_penalty: d.hyphen_penalty.unwrap_or(default.hyphen_penalty),
+                    consecutive_hyphens: d
+                        .consecutive_hyphens
+                        .unwrap_or(default.consecutive_hyphens),
+                }
+            })
+            .unwrap_or_default(),
+        hyphenation: hyphenation(config.hyphenation)?,
+        alignment: config.align.as_deref().unwrap_or("justify").parse()?,
+        last_line: config.last_line.as_deref().unwrap_or("justify").parse()?,
+        kashida: kashida(config.kashida)?,
+        local_variations: config.local_variations.unwrap_or(false),
+        shaper: config.shaper.as_deref().unwrap_or("rustybuzz").parse()?,
+        script: script(config.font.script.as_deref())?,
+        language: language(config.font.language.clone())?,
+        direction: config
+            .font
+            .direction
+            .as_deref()
+            .map(str::parse)
+            .transpose()?,
     };
 
-    let mut variations = config
-        .font
-        .variations
-        .into_iter()
-        .flatten()
-        .filter_map(|v| {
-            let name = v.name.as_bytes().try_into().ok()?;
-            Some(nun::Variation::new_axis(name, v.min, v.max, v.rest))
+    let parallel = config
+        .parallel
+        .map(|p| {
+            let (variations, coordinates) = variations(&p.font)?;
+            Ok::<_, Box<dyn std::error::Error>>(nun::ParallelText {
+                text_path: p.text.into(),
+                font_size: FACTOR as f32 * p.font.size,
+                line_height: p.font.line_height,
+                hyphenation: hyphenation(p.hyphenation)?,
+                placement: p.placement.as_deref().unwrap_or("beneath").parse()?,
+                variations,
+                coordinates,
+                fonts: fonts(&p.font)
+                    .into_iter()
+                    .map(|(path, index)| (path.into(), index))
+                    .collect(),
+                features: p.font.features.into_iter().flatten().collect(),
+                alternates: alternates(p.font.alternates.as_deref())?,
+                script: script(p.font.script.as_deref())?,
+                language: language(p.font.language.clone())?,
+                direction: p.font.direction.as_deref().map(str::parse).transpose()?,
+            })
         })
-        .collect::<Vec<_>>();
+        .transpose()?;
 
-    if variations.is_empty() {
-        variations.push(nun::Variation::new_spacing());
-    }
+    let (variations, coordinates) = variations(&config.font)?;
+    let fonts = fonts(&config.font);
 
     let features = config
         .font
@@ -40,9 +89,190 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     nun::run(
         config.text,
-        config.font.path,
+        &fonts,
         &features,
         variations,
+        &coordinates,
         img_config,
+        parallel,
     )
 }
+
+/// The primary font with the index of its face, followed by its fallbacks, of which
+/// the first face is used.
+fn fonts(font: &config::FontConfig) -> Vec<(String, u32)> {
+    std::iter::once((font.path.clone(), font.index.unwrap_or(0)))
+        .chain(
+            font.fallbacks
+                .iter()
+                .flatten()
+                .map(|path| (path.clone(), 0)),
+        )
+        .collect()
+}
+
+/// The font's variations, and the coordinates of its instance if any.
+fn variations(
+    font: &config::FontConfig
+) -> Result<(Vec<nun::Variation>, nun::Coordinates), Box<dyn std::error::Error>> {
+    let font_data =
+        std::fs::read(&font.path).map_err(|e| format!("Could not read {}: {e}", font.path))?;
+    let index = font.index.unwrap_or(0);
+    let mut axes = nun::font_axes(&font_data, index).map_err(|e| format!("{}: {e}", font.path))?;
+
+    // variations range around the instance
+    let coordinates = match &font.instance {
+        Some(name) => nun::instance_coordinates(&font_data, index, name)?,
+        None => vec![],
+    };
+    for axis in &mut axes {
+        if let Some(&(_, value)) = coordinates.iter().find(|(tag, _)| *tag == axis.tag) {
+            axis.default = value;
+        }
+    }
+
+    let mut variations = font
+        .variations
+        .iter()
+        .flatten()
+        .map(|v| {
+            match v.name.as_str() {
+                "space" => spacing(v.min, v.max, v.rest),
+                "tracking" => tracking(v.min, v.max, v.rest),
+                name => nun::Variation::from_font_axis(&axes, name, v.min, v.max, v.rest),
+            }
+            .map(|var| var.with_weight(v.weight.unwrap_or(1.0)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if variations.is_empty() {
+        variations.push(spacing(None, None, None)?);
+    }
+
+    Ok((variations, coordinates))
+}
+
+/// Scale of the space glyph's width, from 0.25 to 1.25 around 1 unless given.
+fn spacing(
+    min: Option<f32>,
+    max: Option<f32>,
+    rest: Option<f32>,
+) -> Result<nun::Variation, String> {
+    let (min, max, rest) = bounds("Space", (min, max, rest), (0.25, 1.25, 1.0))?;
+    if min < 0.0 {
+        return Err(format!("Space range {min}..{max} goes below zero"));
+    }
+    Ok(nun::Variation::new_spacing(min, max, rest))
+}
+
+/// Letter spacing in thousandths of an em, from -20 to 50 around 0 unless given.
+fn tracking(
+    min: Option<f32>,
+    max: Option<f32>,
+    rest: Option<f32>,
+) -> Result<nun::Variation, String> {
+    let (min, max, rest) = bounds("Tracking", (min, max, rest), (-20.0, 50.0, 0.0))?;
+    Ok(nun::Variation::new_tracking(min, max, rest))
+}
+
+/// Fills in the missing values from `defaults`, with the rest value kept in range.
+fn bounds(
+    name: &str,
+    (min, max, rest): (Option<f32>, Option<f32>, Option<f32>),
+    (default_min, default_max, default_rest): (f32, f32, f32),
+) -> Result<(f32, f32, f32), String> {
+    let min = min.unwrap_or(default_min);
+    let max = max.unwrap_or(default_max);
+    let rest = rest.unwrap_or(default_rest.max(min).min(max));
+
+    if !(min..=max).contains(&rest) {
+        return Err(format!(
+            "{name} rest value {rest} is outside its range {min}..{max}"
+        ));
+    }
+    Ok((min, max, rest))
+}
+
+fn alternates(feature: Option<&str>) -> Result<Option<[u8; 4]>, String> {
+    feature
+        .map(|f| {
+            f.as_bytes()
+                .try_into()
+                .map_err(|_| format!("Feature tags are four characters: {f}"))
+        })
+        .transpose()
+}
+
+/// A script by its ISO 15924 code, such as `Arab`, or its name.
+fn script(script: Option<&str>) -> Result<Option<unicode_script::Script>, String> {
+    script
+        .map(|s| {
+            // codes are written as `Arab`
+            let mut chars = s.chars();
+            let code = chars
+                .next()
+                .map(|c| c.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars.map(|c| c.to_ascii_lowercase()))
+                .collect::<String>();
+            unicode_script::Script::from_short_name(&code)
+                .or_else(|| unicode_script::Script::from_full_name(s))
+                .ok_or_else(|| format!("Unknown script: {s}"))
+        })
+        .transpose()
+}
+
+/// A BCP 47 tag: subtags of up to eight letters and digits, joined by hyphens.
+fn language(lang: Option<String>) -> Result<Option<String>, String> {
+    lang.map(|lang| {
+        let valid = lang
+            .split('-')
+            .all(|t| (1..=8).contains(&t.len()) && t.chars().all(|c| c.is_ascii_alphanumeric()));
+        if valid {
+            Ok(lang)
+        } else {
+            Err(format!("Invalid language tag: {lang}"))
+        }
+    })
+    .transpose()
+}
+
+fn hyphenation(lang: Option<String>) -> Result<Option<hypher::Lang>, String> {
+    lang.map(|lang| {
+        lang.as_bytes()
+            .try_into()
+            .ok()
+            .and_then(hypher::Lang::from_iso)
+            .ok_or_else(|| format!("Unknown hyphenation language: {lang}"))
+    })
+    .transpose()
+}
+
+fn kashida(kashida: Option<config::KashidaConfig>) -> Result<nun::KashidaPolicy, String> {
+    let default = nun::KashidaPolicy::default();
+    let Some(k) = kashida else {
+        return Ok(default);
+    };
+
+    Ok(nun::KashidaPolicy {
+        max_per_word: k.max_per_word.unwrap_or(default.max_per_word),
+        max_per_line: k.max_per_line.or(default.max_per_line),
+        priority: k
+            .priority
+            .into_iter()
+            .flatten()
+            .map(|letter| {
+                let mut chars = letter.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(c),
+                    _ => Err(format!("Kashida priorities are single letters: {letter}")),
+                }
+            })
+            .collect::<Result<_, _>>()?,
+        avoid_last_word: k.avoid_last_word.unwrap_or(default.avoid_last_word),
+        order: match k.order {
+            Some(order) => order.parse()?,
+            None => default.order,
+        },
+    })
+}
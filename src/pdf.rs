@@ -0,0 +1,310 @@
+use crate::draw::{glyph_path, PathOp, PlacedGlyph, RenderFont};
+use ab_glyph::{self as ab, Font as _, ScaleFont as _};
+use pdf_writer::{
+    types::TextRenderingMode, writers::ExtGraphicsState, Content, Name, Pdf, Rect, Ref, Str,
+    TextStr,
+};
+
+pub(crate) struct PdfDocument {
+    width: u32,
+    height: u32,
+    txt_color: [u8; 4],
+    bkg_color: [u8; 4],
+    /// Contents of the finished pages.
+    pages: Vec<Content>,
+    content: Content,
+}
+
+impl PdfDocument {
+    pub fn new(
+        width: u32,
+        height: u32,
+        txt_color: [u8; 4],
+        bkg_color: [u8; 4],
+    ) -> Self {
+        let mut document = Self {
+            width,
+            height,
+            txt_color,
+            bkg_color,
+            pages: vec![],
+            content: Content::new(),
+        };
+        document.paint_background();
+        document
+    }
+
+    /// Finishes the current page and starts drawing on a new one.
+    pub fn new_page(&mut self) {
+        let content = std::mem::replace(&mut self.content, Content::new());
+        self.pages.push(content);
+        self.paint_background();
+    }
+
+    fn paint_background(&mut self) {
+        let [r, g, b, _] = self.bkg_color.map(|c| c as f32 / 255.0);
+        self.content
+            .set_parameters(Name(b"Bg"))
+            .set_fill_rgb(r, g, b)
+            .rect(0.0, 0.0, self.width as f32, self.height as f32)
+            .fill_nonzero();
+
+        let [r, g, b, _] = self.txt_color.map(|c| c as f32 / 255.0);
+        self.content
+            .set_parameters(Name(b"Tx"))
+            .set_fill_rgb(r, g, b);
+    }
+
+    /// Draws the line's glyphs as outlines, wrapping every cluster in an `ActualText`
+    /// span so the text can be searched and copied. Spans are written in logical order,
+    /// which is what PDF readers use to extract text.
+    ///
+    /// Readers only take `ActualText` from spans that show text, so each span also
+    /// shows an invisible space from a standard font, stretched over the cluster's
+    /// glyphs for selection.
+    ///
+    /// `text` is the exact string that was shaped, as `PlacedGlyph::cluster` indexes into it.
+    /// Characters added for layout, at the byte positions in `added`, are left out of the
+    /// spans. These are the tatweels inserted for justification and the hyphen of a
+    /// hyphenated line, while tatweels of the source text are kept.
+    pub fn push_line(
+        &mut self,
+        fonts: &mut [RenderFont],
+        text: &str,
+        glyphs: &[PlacedGlyph],
+        added: &[usize],
+    ) {
+        let mut boundaries = glyphs
+            .iter()
+            .map(|g| g.cluster as usize)
+            .collect::<Vec<_>>();
+        boundaries.push(text.len());
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        let mut logical = glyphs.iter().collect::<Vec<_>>();
+        logical.sort_by_key(|g| g.cluster);
+
+        for cluster in logical.chunk_by(|a, b| a.cluster == b.cluster) {
+            let start = cluster[0].cluster as usize;
+            let end = boundaries
+                .iter()
+                .find(|&&b| b > start)
+                .copied()
+                .unwrap_or(text.len());
+
+            let actual_text = text[start..end]
+                .char_indices()
+                .filter(|(idx, _)| !added.contains(&(start + idx)))
+                .map(|(_, c)| c)
+                .collect::<String>();
+
+            self.content
+                .begin_marked_content_with_properties(Name(b"Span"))
+                .properties()
+                .actual_text(TextStr(&actual_text));
+
+            let (mut left, mut right) = (f32::INFINITY, f32::NEG_INFINITY);
+            let mut em = 0.0_f32;
+            for glyph in cluster {
+                let RenderFont {
+                    font,
+                    scale,
+                    scale_factor,
+                } = fonts[glyph.font].with_variations(glyph.variations);
+                self.push_glyph(font, glyph.id, glyph.position, *scale_factor);
+
+                let advance = font.as_scaled(*scale).h_advance(glyph.id);
+                left = left.min(glyph.position.x);
+                right = right.max(glyph.position.x + advance);
+                em = em.max(scale_factor.horizontal * font.units_per_em().unwrap_or(1000.0));
+            }
+            self.push_invisible_space(left, cluster[0].position.y, right - left, em);
+
+            self.content.end_marked_content();
+        }
+    }
+
+    /// A space of the text layer, `width` wide on the baseline at `y`.
+    fn push_invisible_space(
+        &mut self,
+        x: f32,
+        y: f32,
+        width: f32,
+        size: f32,
+    ) {
+        // Helvetica's space is 278 thousandths of an em
+        let scaling = match width / (0.278 * size) * 100.0 {
+            s if s.is_finite() && s > 0.0 => s,
+            _ => 100.0,
+        };
+        self.content
+            .begin_text()
+            .set_font(Name(b"Layer"), size)
+            .set_text_rendering_mode(TextRenderingMode::Invisible)
+            .set_horizontal_scaling(scaling)
+            .set_text_matrix([1.0, 0.0, 0.0, 1.0, x, self.height as f32 - y])
+            .show(Str(b" "))
+            .end_text();
+    }
+
+    fn push_glyph(
+        &mut self,
+        font: &impl ab::Font,
+        id: ab::GlyphId,
+        position: ab::Point,
+        scale_factor: ab::PxScaleFactor,
+    ) {
+        let Some(path) = glyph_path(font, id, position, scale_factor) else {
+            return;
+        };
+
+        // PDF is y-up
+        let height = self.height as f32;
+        let mut current = position;
+        for op in path {
+            match op {
+                PathOp::MoveTo(p) => self.content.move_to(p.x, height - p.y),
+                PathOp::LineTo(p) => self.content.line_to(p.x, height - p.y),
+                PathOp::QuadTo(c, p) => {
+                    // PDF has no quadratic curves. Elevate to cubic.
+                    let (x0, y0) = (current.x, current.y);
+                    let c1 = (x0 + 2.0 / 3.0 * (c.x - x0), y0 + 2.0 / 3.0 * (c.y - y0));
+                    let c2 = (p.x + 2.0 / 3.0 * (c.x - p.x), p.y + 2.0 / 3.0 * (c.y - p.y));
+                    self.content.cubic_to(
+                        c1.0,
+                        height - c1.1,
+                        c2.0,
+                        height - c2.1,
+                        p.x,
+                        height - p.y,
+                    )
+                }
+                PathOp::CubicTo(c1, c2, p) => self.content.cubic_to(
+                    c1.x,
+                    height - c1.y,
+                    c2.x,
+                    height - c2.y,
+                    p.x,
+                    height - p.y,
+                ),
+                PathOp::Close => self.content.close_path(),
+            };
+
+            match op {
+                PathOp::MoveTo(p)
+                | PathOp::LineTo(p)
+                | PathOp::QuadTo(_, p)
+                | PathOp::CubicTo(_, _, p) => current = p,
+                PathOp::Close => (),
+            }
+        }
+
+        self.content.fill_nonzero();
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.pages.push(self.content);
+
+        let catalog_id = Ref::new(1);
+        let page_tree_id = Ref::new(2);
+        // each page and its content stream
+        let page_ids = (0..self.pages.len() as i32)
+            .map(|i| (Ref::new(3 + 2 * i), Ref::new(4 + 2 * i)))
+            .collect::<Vec<_>>();
+
+        // the standard font of the text layer, which readers have without embedding
+        let font_id = Ref::new(3 + 2 * page_ids.len() as i32);
+
+        let mut pdf = Pdf::new();
+        pdf.type1_font(font_id).base_font(Name(b"Helvetica"));
+        pdf.catalog(catalog_id).pages(page_tree_id);
+        pdf.pages(page_tree_id)
+            .kids(page_ids.iter().map(|&(page_id, _)| page_id))
+            .count(page_ids.len() as i32);
+
+        for (content, &(page_id, content_id)) in self.pages.into_iter().zip(&page_ids) {
+            let mut page = pdf.page(page_id);
+            page.parent(page_tree_id)
+                .media_box(Rect::new(0.0, 0.0, self.width as f32, self.height as f32))
+                .contents(content_id);
+
+            let mut resources = page.resources();
+            let mut states = resources.ext_g_states();
+            states
+                .insert(Name(b"Bg"))
+                .start::<ExtGraphicsState>()
+                .non_stroking_alpha(self.bkg_color[3] as f32 / 255.0);
+            states
+                .insert(Name(b"Tx"))
+                .start::<ExtGraphicsState>()
+                .non_stroking_alpha(self.txt_color[3] as f32 / 255.0);
+            drop(states);
+            resources.fonts().pair(Name(b"Layer"), font_id);
+            drop(resources);
+            drop(page);
+
+            pdf.stream(content_id, &content.finish());
+        }
+
+        pdf.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAQQ: &[u8] = include_bytes!("../fonts/Raqq.ttf");
+
+    #[test]
+    fn spans_show_invisible_text() {
+        let font = ab::FontRef::try_from_slice(RAQQ).unwrap();
+        let scale = font.pt_to_px_scale(80.0).unwrap();
+        let mut fonts = [RenderFont {
+            font: font.clone(),
+            scale,
+            scale_factor: font.as_scaled(scale).scale_factor(),
+        }];
+
+        // the second tatweel and the hyphen were added by the layout
+        let text = "بــسم-";
+        let glyphs = text
+            .char_indices()
+            .map(|(idx, c)| PlacedGlyph {
+                id: font.glyph_id(c),
+                font: 0,
+                position: ab::point(500.0 - 40.0 * idx as f32, 200.0),
+                cluster: idx as u32,
+                variations: &[],
+            })
+            .collect::<Vec<_>>();
+        let mut document = PdfDocument::new(600, 400, [0, 0, 0, 255], [255; 4]);
+        document.push_line(&mut fonts, text, &glyphs, &[4, 10]);
+        let pdf = String::from_utf8_lossy(&document.finish()).into_owned();
+
+        assert!(pdf.contains("/BaseFont /Helvetica"));
+        let spans = pdf.split("/Span <<").skip(1).collect::<Vec<_>>();
+        assert_eq!(spans.len(), text.chars().count());
+        let mut actual_text = String::new();
+        for span in spans {
+            let span = &span[..span.find("EMC").unwrap()];
+            for op in ["BT", "/Layer", "3 Tr", "( ) Tj", "ET"] {
+                assert!(span.contains(op), "{op} in {span}");
+            }
+
+            let value = span.split("/ActualText ").nth(1).unwrap();
+            if let Some(hex) = value.strip_prefix("<FEFF") {
+                let units = hex.as_bytes()[..hex.find('>').unwrap()]
+                    .chunks(4)
+                    .map(|u| u16::from_str_radix(std::str::from_utf8(u).unwrap(), 16).unwrap())
+                    .collect::<Vec<_>>();
+                actual_text += &String::from_utf16(&units).unwrap();
+            } else {
+                assert!(value.starts_with("()"), "{value}");
+            }
+        }
+        // the tatweel from the source stays
+        assert_eq!(actual_text, "بـسم");
+    }
+}
@@ -1,75 +1,687 @@
-#![allow(dead_code)]
-#![allow(unused)]
-
-use std::str::FromStr;
+use crate::{fvar::parse_face, logic::VariationKind, Variation};
 use rustybuzz::{ttf_parser::Tag, Feature};
-use crate::{logic::VariationKind, Variation};
+use std::{error::Error, ops::Range, str::FromStr, sync::Mutex};
+use unicode_properties::{GeneralCategoryGroup, UnicodeGeneralCategory as _};
+use unicode_script::{Script, UnicodeScript as _};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) struct GlyphData {
     pub codepoint: u32,
     pub cluster: u32,
+    /// Index of the face the glyph was shaped with. 0 is the primary font.
+    pub font: usize,
     pub x_advance: i32,
     pub y_advance: i32,
     pub x_offset: i32,
     pub y_offset: i32,
 }
 
+/// Direction text is set in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Ltr,
+    Rtl,
+}
+
+impl Direction {
+    /// Base direction of `text`, from its first strongly directional character.
+    /// Text without one is left to right.
+    pub(crate) fn of(text: &str) -> Self {
+        match unicode_bidi::get_base_direction(text) {
+            unicode_bidi::Direction::Rtl => Self::Rtl,
+            _ => Self::Ltr,
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ltr" => Ok(Self::Ltr),
+            "rtl" => Ok(Self::Rtl),
+            _ => Err(format!("Unknown direction: {s}")),
+        }
+    }
+}
+
+/// What shapers are told about the text, rather than left to guess.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TextProperties {
+    /// Script of all the text. Each run's is detected from its letters if `None`.
+    pub script: Option<Script>,
+    /// BCP 47 language tag, which selects the language's forms of letters.
+    pub language: Option<String>,
+    /// Base direction of paragraphs. Detected from each paragraph if `None`.
+    pub direction: Option<Direction>,
+}
+
 pub trait Shaper<'f> {
+    /// Shapes a run of text in one direction. Glyphs are returned in visual order.
+    ///
+    /// `alternates` are byte positions in `input` of characters to set with the
+    /// shaper's alternates feature, if it has one.
     fn shape_text(
         &mut self,
         input: &str,
         variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
     ) -> Vec<GlyphData>;
+
+    /// Base direction of paragraphs, if it is set rather than detected.
+    fn direction(&self) -> Option<Direction> {
+        None
+    }
 }
 
-pub(crate) struct RustBuzz<'f>(rustybuzz::Face<'f>, Vec<Feature>);
-impl<'f> RustBuzz<'f> {
+/// Shapes a line of text, splitting it into runs with the Unicode Bidi Algorithm.
+/// Glyphs are returned in visual order, and clusters index into `input`.
+///
+/// `base` is the paragraph's direction. If `None` it is the shaper's, or else
+/// detected from `input`.
+pub(crate) fn shape_line<'f>(
+    shaper: &mut impl Shaper<'f>,
+    input: &str,
+    variations: &[Variation],
+    base: Option<Direction>,
+) -> Vec<GlyphData> {
+    shape_line_local(shaper, input, variations, &[], &[], base)
+}
+
+/// Like `shape_line`, but the byte ranges in `words` are shaped with their own
+/// variations. The rest of `input` is shaped with `variations`.
+///
+/// `alternates` are byte positions in `input`, as in `Shaper::shape_text`.
+pub(crate) fn shape_line_local<'f>(
+    shaper: &mut impl Shaper<'f>,
+    input: &str,
+    variations: &[Variation],
+    words: &[(Range<usize>, Vec<Variation>)],
+    alternates: &[usize],
+    base: Option<Direction>,
+) -> Vec<GlyphData> {
+    let base = base.or(shaper.direction()).map(|d| match d {
+        Direction::Ltr => unicode_bidi::Level::ltr(),
+        Direction::Rtl => unicode_bidi::Level::rtl(),
+    });
+    let bidi = unicode_bidi::BidiInfo::new(input, base);
+
+    let mut glyphs = vec![];
+    for paragraph in &bidi.paragraphs {
+        let (levels, runs) = bidi.visual_runs(paragraph, paragraph.range.clone());
+        for run in runs {
+            let direction = if levels[run.start].is_rtl() {
+                Direction::Rtl
+            } else {
+                Direction::Ltr
+            };
+
+            // cut the run where words start and end
+            let mut pieces = vec![];
+            let mut start = run.start;
+            for (word, word_variations) in words {
+                let word = word.start.max(run.start)..word.end.min(run.end);
+                if word.is_empty() {
+                    continue;
+                }
+                if start < word.start {
+                    pieces.push((start..word.start, variations));
+                }
+                pieces.push((word.clone(), word_variations.as_slice()));
+                start = word.end;
+            }
+            if start < run.end {
+                pieces.push((start..run.end, variations));
+            }
+            if direction == Direction::Rtl {
+                pieces.reverse();
+            }
+
+            for (piece, variations) in pieces {
+                let alternates = alternates
+                    .iter()
+                    .filter(|a| piece.contains(a))
+                    .map(|a| a - piece.start)
+                    .collect::<Vec<_>>();
+                glyphs.extend(
+                    shaper
+                        .shape_text(&input[piece.clone()], variations, &alternates, direction)
+                        .into_iter()
+                        .map(|g| GlyphData {
+                            cluster: g.cluster + piece.start as u32,
+                            ..g
+                        }),
+                );
+            }
+        }
+    }
+
+    glyphs
+}
+
+/// Shaping library used to lay out text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    RustBuzz,
+    /// HarfBuzz itself. Needs the `harfbuzz` feature.
+    #[cfg(feature = "harfbuzz")]
+    HarfBuzz,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rustybuzz" => Ok(Self::RustBuzz),
+            #[cfg(feature = "harfbuzz")]
+            "harfbuzz" => Ok(Self::HarfBuzz),
+            #[cfg(not(feature = "harfbuzz"))]
+            "harfbuzz" => Err("nun was built without the harfbuzz feature".to_owned()),
+            _ => Err(format!("Unknown shaper: {s}")),
+        }
+    }
+}
+
+/// The shaper of a `Backend`, chosen at runtime.
+pub(crate) enum AnyShaper<'f> {
+    RustBuzz(RustBuzz<'f>),
+    #[cfg(feature = "harfbuzz")]
+    HarfBuzz(HarfBuzz<'f>),
+}
+impl<'f> AnyShaper<'f> {
+    /// `fonts` holds each font's data and the index of its face.
     pub fn new(
-        font_data: &'f [u8],
+        backend: Backend,
+        fonts: &[(&'f [u8], u32)],
         features: &[String],
+        coordinates: &[([u8; 4], f32)],
+        alternates: Option<[u8; 4]>,
+        properties: &TextProperties,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(match backend {
+            Backend::RustBuzz => Self::RustBuzz(
+                RustBuzz::new(fonts, features, coordinates)?
+                    .with_alternates(alternates)
+                    .with_properties(properties.clone()),
+            ),
+            #[cfg(feature = "harfbuzz")]
+            Backend::HarfBuzz => Self::HarfBuzz(
+                HarfBuzz::new(fonts, features, coordinates)?
+                    .with_alternates(alternates)
+                    .with_properties(properties.clone()),
+            ),
+        })
+    }
+}
+impl<'f> Shaper<'f> for AnyShaper<'f> {
+    fn shape_text(
+        &mut self,
+        input: &str,
+        variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
+    ) -> Vec<GlyphData> {
+        match self {
+            Self::RustBuzz(shaper) => shaper.shape_text(input, variations, alternates, direction),
+            #[cfg(feature = "harfbuzz")]
+            Self::HarfBuzz(shaper) => shaper.shape_text(input, variations, alternates, direction),
+        }
+    }
+
+    fn direction(&self) -> Option<Direction> {
+        match self {
+            Self::RustBuzz(shaper) => shaper.direction(),
+            #[cfg(feature = "harfbuzz")]
+            Self::HarfBuzz(shaper) => shaper.direction(),
+        }
+    }
+}
+
+/// What a shaping depends on: the text, its direction, its alternates, and the
+/// quantized values of the variations.
+type CacheKey = (Box<str>, Direction, Vec<usize>, Vec<(VariationKind, u32)>);
+
+/// Remembers what it shaped, as lines are shaped many times over while they are
+/// fit and broken. Variations are quantized, so that the values the search
+/// tries close to each other share results.
+///
+/// If the font has no lookups across spaces, words can be shaped on their own
+/// and put together, so that they are shared between the lines they can be in.
+pub(crate) struct Cached<S> {
+    shaper: S,
+    cache: hashbrown::HashMap<CacheKey, Vec<GlyphData>>,
+    by_word: bool,
+}
+impl<S> Cached<S> {
+    /// `by_word` shapes words on their own. Only set it if `words_shape_apart`.
+    pub fn new(
+        shaper: S,
+        by_word: bool,
+    ) -> Self {
+        Self {
+            shaper,
+            cache: hashbrown::HashMap::new(),
+            by_word,
+        }
+    }
+}
+impl<'f, S: Shaper<'f>> Cached<S> {
+    fn shape_cached(
+        &mut self,
+        input: &str,
+        variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
+    ) -> Vec<GlyphData> {
+        let variations = variations
+            .iter()
+            .map(Variation::quantized)
+            .collect::<Vec<_>>();
+        let key = (
+            input.into(),
+            direction,
+            alternates.to_vec(),
+            variations
+                .iter()
+                .map(|v| (v.kind, v.current_value.to_bits()))
+                .collect(),
+        );
+        self.cache
+            .entry(key)
+            .or_insert_with(|| {
+                self.shaper
+                    .shape_text(input, &variations, alternates, direction)
+            })
+            .clone()
+    }
+}
+impl<'f, S: Shaper<'f>> Shaper<'f> for Cached<S> {
+    fn shape_text(
+        &mut self,
+        input: &str,
+        variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
+    ) -> Vec<GlyphData> {
+        if !self.by_word {
+            return self.shape_cached(input, variations, alternates, direction);
+        }
+
+        // each word keeps the spaces after it, so that only the last letter of
+        // `input` is left untracked
+        let mut words: Vec<Range<usize>> = vec![];
+        for (idx, c) in input.char_indices() {
+            let end = idx + c.len_utf8();
+            match words.last_mut() {
+                Some(word) if c.is_whitespace() || !input[..idx].ends_with(char::is_whitespace) => {
+                    word.end = end;
+                }
+                _ => words.push(idx..end),
+            }
+        }
+        if direction == Direction::Rtl {
+            words.reverse();
+        }
+
+        let mut glyphs = vec![];
+        for word in words {
+            let alternates = alternates
+                .iter()
+                .filter(|a| word.contains(a))
+                .map(|a| a - word.start)
+                .collect::<Vec<_>>();
+            glyphs.extend(
+                self.shape_cached(&input[word.clone()], variations, &alternates, direction)
+                    .into_iter()
+                    .map(|g| GlyphData {
+                        cluster: g.cluster + word.start as u32,
+                        ..g
+                    }),
+            );
+        }
+        glyphs
+    }
+
+    fn direction(&self) -> Option<Direction> {
+        self.shaper.direction()
+    }
+}
+
+/// Whether shaping the words of `sample` on their own gives the same glyphs as
+/// shaping it whole, which it does unless the font's lookups reach across spaces.
+pub(crate) fn words_shape_apart<'f>(
+    shaper: impl Shaper<'f>,
+    sample: &str,
+) -> bool {
+    let mut words = Cached::new(shaper, true);
+    let apart = shape_line(&mut words, sample, &[], None);
+    apart == shape_line(&mut words.shaper, sample, &[], None)
+}
+
+/// Shapers shared by the threads that lay out lines. A thread takes one for as
+/// long as it shapes, and puts it back with what it cached for the next.
+pub(crate) struct ShaperPool<'p, S> {
+    shapers: Mutex<Vec<S>>,
+    new: Box<dyn Fn() -> S + Send + Sync + 'p>,
+}
+impl<'p, S> ShaperPool<'p, S> {
+    pub fn new(new: impl Fn() -> S + Send + Sync + 'p) -> Self {
+        Self {
+            shapers: Mutex::new(vec![]),
+            new: Box::new(new),
+        }
+    }
+
+    pub fn with<R>(
+        &self,
+        f: impl FnOnce(&mut S) -> R,
+    ) -> R {
+        let shaper = self.shapers.lock().unwrap().pop();
+        let mut shaper = shaper.unwrap_or_else(|| (self.new)());
+        let result = f(&mut shaper);
+        self.shapers.lock().unwrap().push(shaper);
+        result
+    }
+}
+
+/// Splits `input` into runs of the same face, in logical order. Whitespace and
+/// combining marks stay with the preceding run if its face covers them.
+///
+/// `covers` tells whether the face at an index has a glyph for a character.
+fn font_runs(
+    input: &str,
+    face_count: usize,
+    covers: impl Fn(usize, char) -> bool,
+) -> Vec<(usize, Range<usize>)> {
+    let mut runs: Vec<(usize, Range<usize>)> = vec![];
+    for (idx, c) in input.char_indices() {
+        let inherits = c.is_whitespace()
+            || c.general_category_group() == GeneralCategoryGroup::Mark
+            || matches!(c, '\u{200C}' | '\u{200D}');
+        let font = match runs.last() {
+            Some(&(font, _)) if inherits && covers(font, c) => font,
+            // nothing covers it. leave it to the primary face's .notdef
+            _ => (0..face_count).find(|&f| covers(f, c)).unwrap_or(0),
+        };
+
+        match runs.last_mut() {
+            Some((f, range)) if *f == font => range.end = idx + c.len_utf8(),
+            _ => runs.push((font, idx..idx + c.len_utf8())),
+        }
+    }
+    runs
+}
+
+/// Splits `input` into runs of one script, in logical order. Characters shared
+/// by scripts, such as spaces, digits and marks, stay in the run they are in.
+fn script_runs(input: &str) -> Vec<(Option<Script>, Range<usize>)> {
+    let mut runs: Vec<(Option<Script>, Range<usize>)> = vec![];
+    for (idx, c) in input.char_indices() {
+        let end = idx + c.len_utf8();
+        let script = Some(c.script())
+            .filter(|s| !matches!(s, Script::Common | Script::Inherited | Script::Unknown));
+
+        match runs.last_mut() {
+            Some((s, range)) if script.is_none() || *s == script => range.end = end,
+            // what comes before the first letter is in its script
+            Some((s @ None, range)) => {
+                *s = script;
+                range.end = end;
+            }
+            _ => runs.push((script, idx..end)),
+        }
+    }
+    runs
+}
+
+/// Shapes each of the `font_runs` of `input` with `shape_run`, which is given the
+/// face, the script, the run's text and its alternates. Glyphs are returned in
+/// visual order.
+///
+/// Runs are split where their script changes, unless `script` is given for all.
+fn shape_font_runs(
+    runs: Vec<(usize, Range<usize>)>,
+    input: &str,
+    alternates: &[usize],
+    (direction, script): (Direction, Option<Script>),
+    mut shape_run: impl FnMut(usize, Option<Script>, &str, &[usize]) -> Vec<GlyphData>,
+) -> Vec<GlyphData> {
+    let mut runs = runs
+        .into_iter()
+        .flat_map(|(font, range)| match script {
+            Some(script) => vec![(font, Some(script), range)],
+            None => script_runs(&input[range.clone()])
+                .into_iter()
+                .map(|(script, r)| (font, script, range.start + r.start..range.start + r.end))
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+    if direction == Direction::Rtl {
+        runs.reverse();
+    }
+
+    let mut glyphs = vec![];
+    for (font, script, range) in runs {
+        let alternates = alternates
+            .iter()
+            .filter(|a| range.contains(a))
+            .map(|a| a - range.start)
+            .collect::<Vec<_>>();
+        glyphs.extend(
+            shape_run(font, script, &input[range.clone()], &alternates)
+                .into_iter()
+                .map(|g| GlyphData {
+                    cluster: g.cluster + range.start as u32,
+                    ..g
+                }),
+        );
+    }
+    glyphs
+}
+
+/// Axis values to shape with: the `variations` on axes, and `coordinates` for
+/// the axes they leave out.
+fn axis_values(
+    coordinates: &[([u8; 4], f32)],
+    variations: &[Variation],
+) -> Vec<([u8; 4], f32)> {
+    let axes = variations
+        .iter()
+        .filter_map(|v| match v.kind {
+            VariationKind::Axis(tag) => Some((tag, v.current_value)),
+            VariationKind::Spacing | VariationKind::Tracking => None,
+        })
+        .collect::<Vec<_>>();
+    coordinates
+        .iter()
+        .filter(|(tag, _)| axes.iter().all(|(t, _)| t != tag))
+        .chain(&axes)
+        .copied()
+        .collect()
+}
+
+/// Advances changed by the spacing and tracking variations, which shapers know
+/// nothing about.
+struct Spacing<'a> {
+    input: &'a str,
+    space: Option<u32>,
+    space_scale: Option<f32>,
+    tracking: i32,
+    last_cluster: Option<u32>,
+}
+impl<'a> Spacing<'a> {
+    /// `space` is the face's space glyph, and `clusters` those of the shaped run.
+    fn new(
+        input: &'a str,
+        variations: &[Variation],
+        (space, units_per_em): (Option<u32>, u32),
+        clusters: impl Iterator<Item = u32>,
     ) -> Self {
+        let find = |kind: fn(&VariationKind) -> bool| {
+            variations
+                .iter()
+                .find(|v| kind(&v.kind))
+                .map(|v| v.current_value)
+        };
+        Self {
+            input,
+            space,
+            space_scale: find(|k| matches!(k, VariationKind::Spacing)),
+            tracking: find(|k| matches!(k, VariationKind::Tracking))
+                .map_or(0, |t| (t * units_per_em as f32 / 1000.0) as i32),
+            last_cluster: clusters.max(),
+        }
+    }
+
+    /// Letters are tracked apart, except for the last one and those of joining
+    /// scripts, whose connections would break.
+    fn tracked(
+        &self,
+        cluster: u32,
+    ) -> bool {
+        Some(cluster) != self.last_cluster
+            && self.input[cluster as usize..]
+                .chars()
+                .next()
+                .is_some_and(|c| {
+                    !c.is_whitespace()
+                        && unicode_joining_type::get_joining_type(c)
+                            == unicode_joining_type::JoiningType::NonJoining
+                })
+    }
+
+    fn advance(
+        &self,
+        glyph_id: u32,
+        cluster: u32,
+        x_advance: i32,
+    ) -> i32 {
+        if self.space == Some(glyph_id) {
+            self.space_scale
+                .map_or(x_advance, |scale| (x_advance as f32 * scale) as i32)
+        } else if x_advance != 0 && self.tracking != 0 && self.tracked(cluster) {
+            x_advance + self.tracking
+        } else {
+            x_advance
+        }
+    }
+}
+
+/// Shapes with the first face, falling back to the later faces for characters
+/// the earlier ones do not cover. Axes without a variation are set at the
+/// given coordinates.
+pub(crate) struct RustBuzz<'f>(
+    Vec<rustybuzz::Face<'f>>,
+    Vec<Feature>,
+    Vec<([u8; 4], f32)>,
+    Option<Tag>,
+    TextProperties,
+);
+impl<'f> RustBuzz<'f> {
+    pub fn new(
+        fonts: &[(&'f [u8], u32)],
+        features: &[String],
+        coordinates: &[([u8; 4], f32)],
+    ) -> Result<Self, Box<dyn Error>> {
         let features = features
             .iter()
             .filter_map(|f| Feature::from_str(f).ok())
             .collect();
-        Self(rustybuzz::Face::from_slice(font_data, 0).unwrap(), features)
+        let faces = fonts
+            .iter()
+            .map(|&(data, index)| parse_face(data, index).map(rustybuzz::Face::from_face))
+            .collect::<Result<_, _>>()?;
+        Ok(Self(
+            faces,
+            features,
+            coordinates.to_vec(),
+            None,
+            TextProperties::default(),
+        ))
     }
-}
-impl<'f> Shaper<'f> for RustBuzz<'f> {
-    fn shape_text(
+
+    /// Sets the feature applied to the alternates given to `shape_text`, such as `jalt`.
+    pub fn with_alternates(
+        mut self,
+        feature: Option<[u8; 4]>,
+    ) -> Self {
+        self.3 = feature.map(|tag| Tag::from_bytes(&tag));
+        self
+    }
+
+    pub fn with_properties(
+        mut self,
+        properties: TextProperties,
+    ) -> Self {
+        self.4 = properties;
+        self
+    }
+
+    fn shape_run(
         &mut self,
+        (font, script): (usize, Option<Script>),
         input: &str,
         variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
     ) -> Vec<GlyphData> {
+        // fallback faces are measured in the primary face's units
+        let units = self.0[0].units_per_em() as f32 / self.0[font].units_per_em() as f32;
+        let to_primary = |v: i32| (v as f32 * units).round() as i32;
+
+        let face = &mut self.0[font];
+
         let mut buffer = rustybuzz::UnicodeBuffer::new();
         buffer.push_str(input);
+        buffer.set_direction(match direction {
+            Direction::Ltr => rustybuzz::Direction::LeftToRight,
+            Direction::Rtl => rustybuzz::Direction::RightToLeft,
+        });
+        if let Some(script) = script.and_then(|s| {
+            rustybuzz::Script::from_iso15924_tag(Tag::from_bytes_lossy(s.short_name().as_bytes()))
+        }) {
+            buffer.set_script(script);
+        }
+        if let Some(language) = self.4.language.as_deref().and_then(|l| l.parse().ok()) {
+            buffer.set_language(language);
+        }
 
-        self.0.set_variations(
-            &variations
+        face.set_variations(
+            &axis_values(&self.2, variations)
                 .iter()
-                .filter_map(|v| match v.kind {
-                    VariationKind::Axis(tag) => Some(rustybuzz::Variation {
-                        tag: Tag::from_bytes(&tag),
-                        value: v.current_value,
-                    }),
-                    VariationKind::Spacing => None,
+                .map(|(tag, value)| rustybuzz::Variation {
+                    tag: Tag::from_bytes(tag),
+                    value: *value,
                 })
                 .collect::<Vec<_>>(),
         );
 
-        let output = rustybuzz::shape(&self.0, &self.1, buffer);
+        let mut features = self.1.clone();
+        if let Some(tag) = self.3 {
+            // `Feature::new` makes the end inclusive, but it is compared as exclusive
+            features.extend(alternates.iter().map(|&a| Feature {
+                tag,
+                value: 1,
+                start: a as u32,
+                end: a as u32 + 1,
+            }));
+        }
 
-        let space = self
-            .0
-            .glyph_index(' ')
-            .expect("Font does not hace a space character.");
-        let adjust_space = |space_width| match variations
-            .iter()
-            .find(|v| matches!(v.kind, VariationKind::Spacing))
-        {
-            Some(v) => (space_width as f32 * v.current_value) as i32,
-            None => space_width,
-        };
+        let output = rustybuzz::shape(face, &features, buffer);
+
+        let spacing = Spacing::new(
+            input,
+            variations,
+            (
+                face.glyph_index(' ').map(|g| g.0 as u32),
+                face.units_per_em() as u32,
+            ),
+            output.glyph_infos().iter().map(|i| i.cluster),
+        );
 
         output
             .glyph_infos()
@@ -78,62 +690,463 @@ impl<'f> Shaper<'f> for RustBuzz<'f> {
             .map(|(i, p)| GlyphData {
                 codepoint: i.glyph_id,
                 cluster: i.cluster,
-                x_advance: if i.glyph_id == space.0 as u32 {
-                    adjust_space(p.x_advance)
-                } else {
-                    p.x_advance
-                },
-                y_advance: p.y_advance,
-                x_offset: p.x_offset,
-                y_offset: p.y_offset,
+                font,
+                x_advance: to_primary(spacing.advance(i.glyph_id, i.cluster, p.x_advance)),
+                y_advance: to_primary(p.y_advance),
+                x_offset: to_primary(p.x_offset),
+                y_offset: to_primary(p.y_offset),
+            })
+            .collect()
+    }
+}
+impl<'f> Shaper<'f> for RustBuzz<'f> {
+    fn shape_text(
+        &mut self,
+        input: &str,
+        variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
+    ) -> Vec<GlyphData> {
+        let runs = font_runs(input, self.0.len(), |font, c| {
+            self.0[font].glyph_index(c).is_some()
+        });
+        let script = self.4.script;
+        shape_font_runs(
+            runs,
+            input,
+            alternates,
+            (direction, script),
+            |font, script, run, alternates| {
+                self.shape_run((font, script), run, variations, alternates, direction)
+            },
+        )
+    }
+
+    fn direction(&self) -> Option<Direction> {
+        self.4.direction
+    }
+}
+
+/// Like `RustBuzz`, but shapes with HarfBuzz itself, to compare against it or
+/// for fonts that rustybuzz gets wrong.
+#[cfg(feature = "harfbuzz")]
+pub(crate) struct HarfBuzz<'f>(
+    Vec<harfbuzz_rs::Owned<harfbuzz_rs::Font<'f>>>,
+    Vec<Feature>,
+    Vec<([u8; 4], f32)>,
+    Option<[u8; 4]>,
+    TextProperties,
+);
+#[cfg(feature = "harfbuzz")]
+impl<'f> HarfBuzz<'f> {
+    pub fn new(
+        fonts: &[(&'f [u8], u32)],
+        features: &[String],
+        coordinates: &[([u8; 4], f32)],
+    ) -> Result<Self, Box<dyn Error>> {
+        // features are parsed with rustybuzz, which follows HarfBuzz's syntax
+        let features = features
+            .iter()
+            .filter_map(|f| Feature::from_str(f).ok())
+            .collect();
+        // HarfBuzz takes faces it cannot read as empty, so they are checked first
+        let fonts = fonts
+            .iter()
+            .map(|&(data, index)| {
+                parse_face(data, index)?;
+                Ok(harfbuzz_rs::Font::new(harfbuzz_rs::Face::from_bytes(
+                    data, index,
+                )))
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        Ok(Self(
+            fonts,
+            features,
+            coordinates.to_vec(),
+            None,
+            TextProperties::default(),
+        ))
+    }
+
+    /// Sets the feature applied to the alternates given to `shape_text`, such as `jalt`.
+    pub fn with_alternates(
+        mut self,
+        feature: Option<[u8; 4]>,
+    ) -> Self {
+        self.3 = feature;
+        self
+    }
+
+    pub fn with_properties(
+        mut self,
+        properties: TextProperties,
+    ) -> Self {
+        self.4 = properties;
+        self
+    }
+
+    fn shape_run(
+        &mut self,
+        (font, script): (usize, Option<Script>),
+        input: &str,
+        variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
+    ) -> Vec<GlyphData> {
+        use harfbuzz_rs::Feature as HbFeature;
+
+        let upem = |font: &harfbuzz_rs::Font| font.face().upem();
+        // fallback faces are measured in the primary face's units
+        let units = upem(&self.0[0]) as f32 / upem(&self.0[font]) as f32;
+        let to_primary = |v: i32| (v as f32 * units).round() as i32;
+
+        let hb_font = &mut self.0[font];
+
+        let mut buffer = harfbuzz_rs::UnicodeBuffer::new()
+            .add_str(input)
+            .set_direction(match direction {
+                Direction::Ltr => harfbuzz_rs::Direction::Ltr,
+                Direction::Rtl => harfbuzz_rs::Direction::Rtl,
+            });
+        if let Some(tag) = script.and_then(|s| <[u8; 4]>::try_from(s.short_name().as_bytes()).ok())
+        {
+            buffer = buffer.set_script(harfbuzz_rs::Tag::from(&tag));
+        }
+        if let Some(language) = self.4.language.as_deref().and_then(|l| l.parse().ok()) {
+            buffer = buffer.set_language(language);
+        }
+
+        hb_font.set_variations(
+            &axis_values(&self.2, variations)
+                .iter()
+                .map(|(tag, value)| harfbuzz_rs::Variation::new(tag, *value))
+                .collect::<Vec<_>>(),
+        );
+
+        let mut features = self
+            .1
+            .iter()
+            .map(|f| HbFeature::new(&f.tag.to_bytes(), f.value, f.start as usize..f.end as usize))
+            .collect::<Vec<_>>();
+        if let Some(tag) = &self.3 {
+            features.extend(alternates.iter().map(|&a| HbFeature::new(tag, 1, a..a + 1)));
+        }
+
+        let output = harfbuzz_rs::shape(hb_font, buffer, &features);
+
+        let spacing = Spacing::new(
+            input,
+            variations,
+            (hb_font.get_nominal_glyph(' '), upem(hb_font)),
+            output.get_glyph_infos().iter().map(|i| i.cluster),
+        );
+
+        output
+            .get_glyph_infos()
+            .iter()
+            .zip(output.get_glyph_positions())
+            .map(|(i, p)| GlyphData {
+                codepoint: i.codepoint,
+                cluster: i.cluster,
+                font,
+                x_advance: to_primary(spacing.advance(i.codepoint, i.cluster, p.x_advance)),
+                y_advance: to_primary(p.y_advance),
+                x_offset: to_primary(p.x_offset),
+                y_offset: to_primary(p.y_offset),
             })
             .collect()
     }
 }
+#[cfg(feature = "harfbuzz")]
+impl<'f> Shaper<'f> for HarfBuzz<'f> {
+    fn shape_text(
+        &mut self,
+        input: &str,
+        variations: &[Variation],
+        alternates: &[usize],
+        direction: Direction,
+    ) -> Vec<GlyphData> {
+        let runs = font_runs(input, self.0.len(), |font, c| {
+            self.0[font].get_nominal_glyph(c).is_some()
+        });
+        let script = self.4.script;
+        shape_font_runs(
+            runs,
+            input,
+            alternates,
+            (direction, script),
+            |font, script, run, alternates| {
+                self.shape_run((font, script), run, variations, alternates, direction)
+            },
+        )
+    }
+
+    fn direction(&self) -> Option<Direction> {
+        self.4.direction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAQQ: &[u8] = include_bytes!("../fonts/Raqq.ttf");
+    const GEORAMA: &[u8] = include_bytes!("../fonts/Georama-VariableFont_wdth,wght.ttf");
+    const NOTO_ARABIC: &[u8] = include_bytes!("../fonts/NotoArabic.ttf");
+    const ESTEDAD: &[u8] = include_bytes!("../fonts/Estedad-FD[KSHD,wght].ttf");
+
+    /// A font collection of `fonts`, in order. Table offsets count from the start of
+    /// the collection, so each font's are moved by where the font lands in it.
+    fn collection(fonts: &[&[u8]]) -> Vec<u8> {
+        let mut data = b"ttcf\0\x01\0\0".to_vec();
+        data.extend((fonts.len() as u32).to_be_bytes());
+        let mut start = data.len() + 4 * fonts.len();
+        let mut faces = vec![];
+        for font in fonts {
+            data.extend((start as u32).to_be_bytes());
+            let mut face = font.to_vec();
+            for table in 0..u16::from_be_bytes([font[4], font[5]]) as usize {
+                let at = 12 + 16 * table + 8;
+                let offset = u32::from_be_bytes(face[at..at + 4].try_into().unwrap());
+                face[at..at + 4].copy_from_slice(&(offset + start as u32).to_be_bytes());
+            }
+            start += face.len();
+            faces.push(face);
+        }
+        data.extend(faces.concat());
+        data
+    }
+
+    fn width(glyphs: &[GlyphData]) -> i32 {
+        glyphs.iter().map(|g| g.x_advance).sum()
+    }
+
+    fn rtl_clusters_in_visual_order<'f>(shaper: &mut impl Shaper<'f>) {
+        let text = "بسم الله";
+        let glyphs = shaper.shape_text(text, &[], &[], Direction::Rtl);
+        assert!(!glyphs.is_empty());
+        assert!(glyphs.windows(2).all(|g| g[0].cluster >= g[1].cluster));
+        assert!(glyphs
+            .iter()
+            .all(|g| text.is_char_boundary(g.cluster as usize)));
+    }
+
+    fn spacing_scales_spaces<'f>(shaper: &mut impl Shaper<'f>) {
+        let mut shape = |scale| {
+            let spacing = Variation::new_spacing(0.0, 2.0, scale);
+            width(&shaper.shape_text("بب بب", &[spacing], &[], Direction::Rtl))
+        };
+        let (narrow, rest, wide) = (shape(0.5), shape(1.0), shape(1.5));
+        assert!(narrow < rest && rest < wide);
+        assert!((wide - rest).abs_diff(rest - narrow) <= 1);
+    }
+
+    fn tracking_spares_joining_scripts<'f>(shaper: &mut impl Shaper<'f>) {
+        let tracking = Variation::new_tracking(0.0, 100.0, 100.0);
+
+        let arabic = shaper.shape_text("بسم", &[], &[], Direction::Rtl);
+        let tracked = shaper.shape_text("بسم", &[tracking], &[], Direction::Rtl);
+        assert_eq!(width(&arabic), width(&tracked));
+
+        // two of three letters are tracked, by a tenth of Georama's 1000 units per em
+        let latin = shaper.shape_text("abc", &[], &[], Direction::Ltr);
+        let tracked = shaper.shape_text("abc", &[tracking], &[], Direction::Ltr);
+        assert_eq!(width(&tracked), width(&latin) + 200);
+    }
+
+    fn axes_change_advances<'f>(shaper: &mut impl Shaper<'f>) {
+        let mut shape = |value| {
+            let wdth = Variation::new_axis(*b"wdth", 62.5, 150.0, value);
+            width(&shaper.shape_text("nun", &[wdth], &[], Direction::Ltr))
+        };
+        assert!(shape(62.5) < shape(150.0));
+    }
+
+    fn falls_back_to_later_faces<'f>(shaper: &mut impl Shaper<'f>) {
+        let glyphs = shaper.shape_text("ab", &[], &[], Direction::Ltr);
+        assert!(glyphs.iter().all(|g| g.font == 0));
+        let glyphs = shaper.shape_text("بب", &[], &[], Direction::Rtl);
+        assert!(glyphs.iter().all(|g| g.font == 1 && g.codepoint != 0));
+    }
+
+    fn alternates_are_set_where_given<'f>(shaper: &mut impl Shaper<'f>) {
+        let glyphs = shaper.shape_text("aa", &[], &[1], Direction::Ltr);
+        assert_eq!(glyphs.len(), 2);
+        assert_ne!(glyphs[0].codepoint, glyphs[1].codepoint);
+    }
+
+    fn faces_are_picked_by_index<'f>(
+        collected: &mut impl Shaper<'f>,
+        single: &mut impl Shaper<'f>,
+    ) {
+        assert_eq!(
+            collected.shape_text("nun", &[], &[], Direction::Ltr),
+            single.shape_text("nun", &[], &[], Direction::Ltr)
+        );
+    }
+
+    fn languages_select_their_forms<'f>(
+        plain: &mut impl Shaper<'f>,
+        urdu: &mut impl Shaper<'f>,
+    ) {
+        // Urdu has its own forms of these digits
+        let digits = "۴۶۷";
+        let ids = |glyphs: Vec<GlyphData>| glyphs.iter().map(|g| g.codepoint).collect::<Vec<_>>();
+        assert_ne!(
+            ids(plain.shape_text(digits, &[], &[], Direction::Rtl)),
+            ids(urdu.shape_text(digits, &[], &[], Direction::Rtl))
+        );
+    }
+
+    #[test]
+    fn runs_split_by_script() {
+        let text = "(abc) بسم ١٢ الله, def";
+        let runs = script_runs(text)
+            .into_iter()
+            .map(|(script, range)| (script, &text[range]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            runs,
+            [
+                (Some(Script::Latin), "(abc) "),
+                (Some(Script::Arabic), "بسم ١٢ الله, "),
+                (Some(Script::Latin), "def"),
+            ]
+        );
+    }
+
+    /// Runs the tests above with a backend, given as a function of the fonts, the
+    /// features and the alternates feature.
+    macro_rules! shaping_tests {
+        ($backend:ident, $new:expr) => {
+            mod $backend {
+                use super::*;
+
+                fn shaper(fonts: &[&'static [u8]]) -> impl Shaper<'static> {
+                    let fonts = fonts.iter().map(|&f| (f, 0)).collect::<Vec<_>>();
+                    $new(&fonts, &[], None).unwrap()
+                }
+
+                #[test]
+                fn rtl_clusters_in_visual_order() {
+                    super::rtl_clusters_in_visual_order(&mut shaper(&[RAQQ]));
+                }
+
+                #[test]
+                fn spacing_scales_spaces() {
+                    super::spacing_scales_spaces(&mut shaper(&[RAQQ]));
+                }
+
+                #[test]
+                fn tracking_spares_joining_scripts() {
+                    super::tracking_spares_joining_scripts(&mut shaper(&[GEORAMA, RAQQ]));
+                }
+
+                #[test]
+                fn axes_change_advances() {
+                    super::axes_change_advances(&mut shaper(&[GEORAMA]));
+                }
 
-// pub(crate) struct HarfBuzz<'f>(harfbuzz_rs::Owned<harfbuzz_rs::Font<'f>>);
-// impl<'f> HarfBuzz<'f> {
-//     pub fn new(font_data: &'f [u8]) -> Self {
-//         Self(harfbuzz_rs::Font::new(harfbuzz_rs::Face::from_bytes(font_data, 0)))
-//     }
-// }
-// impl<'f> Shaper<'f> for HarfBuzz<'f> {
-//     fn shape_text(&mut self, input: &str, variations: &[Variation]) -> Vec<GlyphData> {
-//         let buffer = harfbuzz_rs::UnicodeBuffer::new().add_str(input);
-//         self.0.set_variations(
-//             &variations
-//                 .iter()
-//                 .filter_map(|v| match v.kind {
-//                     VariationKind::Axis(tag) => {
-//                         Some(harfbuzz_rs::Variation::new(&tag, v.current_value))
-//                     }
-//                     VariationKind::Spacing => None,
-//                 })
-//                 .collect::<Vec<_>>(),
-//         );
-
-//         let output = harfbuzz_rs::shape(&self.0, buffer, &[]);
-
-//         let space = self.0.get_nominal_glyph(' ').expect("Font does not hace a space character.");
-//         let space_width = self.0.get_glyph_h_advance(space);
-//         let space_width = match variations.iter().find(|v| matches!(v.kind, VariationKind::Spacing))
-//         {
-//             Some(v) => (space_width as f32 * v.current_value) as i32,
-//             None => space_width,
-//         };
-
-//         output
-//             .get_glyph_infos()
-//             .iter()
-//             .zip(output.get_glyph_positions())
-//             .map(|(i, p)| GlyphData {
-//                 codepoint: i.codepoint,
-//                 cluster: i.cluster,
-//                 x_advance: if i.codepoint == space { space_width } else { p.x_advance },
-//                 y_advance: p.y_advance,
-//                 x_offset: p.x_offset,
-//                 y_offset: p.y_offset,
-//             })
-//             .collect()
-//     }
-// }
+                #[test]
+                fn falls_back_to_later_faces() {
+                    super::falls_back_to_later_faces(&mut shaper(&[GEORAMA, NOTO_ARABIC]));
+                }
+
+                #[test]
+                fn languages_select_their_forms() {
+                    super::languages_select_their_forms(
+                        &mut shaper(&[ESTEDAD]),
+                        &mut $new(&[(ESTEDAD, 0)], &[], None).unwrap().with_properties(
+                            TextProperties {
+                                language: Some("ur".to_owned()),
+                                ..TextProperties::default()
+                            },
+                        ),
+                    );
+                }
+
+                #[test]
+                fn alternates_are_set_where_given() {
+                    super::alternates_are_set_where_given(
+                        &mut $new(&[(GEORAMA, 0)], &[], Some(*b"smcp")).unwrap(),
+                    );
+                }
+
+                #[test]
+                fn faces_are_picked_by_index() {
+                    let fonts = collection(&[RAQQ, GEORAMA]);
+                    super::faces_are_picked_by_index(
+                        &mut $new(&[(fonts.as_slice(), 1)], &[], None).unwrap(),
+                        &mut $new(&[(GEORAMA, 0)], &[], None).unwrap(),
+                    );
+                }
+
+                #[test]
+                fn missing_faces_are_errors() {
+                    let fonts = collection(&[RAQQ, GEORAMA]);
+                    assert!($new(&[(fonts.as_slice(), 2)], &[], None).is_err());
+                    assert!($new(&[(RAQQ, 1)], &[], None).is_err());
+                    assert!($new(&[(&RAQQ[..100], 0)], &[], None).is_err());
+                }
+            }
+        };
+    }
+
+    shaping_tests!(rustybuzz, |fonts, features, alternates| {
+        RustBuzz::new(fonts, features, &[]).map(|s| s.with_alternates(alternates))
+    });
+    #[cfg(feature = "harfbuzz")]
+    shaping_tests!(harfbuzz, |fonts, features, alternates| {
+        HarfBuzz::new(fonts, features, &[]).map(|s| s.with_alternates(alternates))
+    });
+
+    #[test]
+    fn words_shaped_apart_match_whole_lines() {
+        let text = "abc def, ghi\nبسم الله الرحمن";
+        let variations = [
+            Variation::new_axis(*b"wdth", 62.5, 150.0, 80.0),
+            Variation::new_spacing(0.5, 1.5, 1.2),
+            Variation::new_tracking(0.0, 50.0, 30.0),
+        ];
+        let shaper = || RustBuzz::new(&[(GEORAMA, 0), (RAQQ, 0)], &[], &[]).unwrap();
+
+        assert!(words_shape_apart(shaper(), text));
+        let mut cached = Cached::new(shaper(), true);
+        assert_eq!(
+            shape_line(&mut cached, text, &variations, None),
+            shape_line(
+                &mut shaper(),
+                text,
+                &variations.map(|v| v.quantized()),
+                None
+            )
+        );
+    }
+
+    #[cfg(feature = "harfbuzz")]
+    #[test]
+    fn backends_agree() {
+        let text = "بِسْمِ اللَّهِ الرَّحْمَٰنِ الرَّحِيمِ";
+        let wght = Variation::new_axis(*b"wght", 100.0, 900.0, 700.0);
+        let glyphs = |shaper: &mut AnyShaper| {
+            shape_line(shaper, text, &[wght], Some(Direction::Rtl))
+                .into_iter()
+                .map(|g| (g.codepoint, g.cluster, g.x_advance, g.x_offset, g.y_offset))
+                .collect::<Vec<_>>()
+        };
+        let [rustybuzz, harfbuzz] = [Backend::RustBuzz, Backend::HarfBuzz].map(|backend| {
+            glyphs(
+                &mut AnyShaper::new(
+                    backend,
+                    &[(NOTO_ARABIC, 0)],
+                    &[],
+                    &[],
+                    None,
+                    &TextProperties::default(),
+                )
+                .unwrap(),
+            )
+        });
+        assert_eq!(rustybuzz, harfbuzz);
+    }
+}
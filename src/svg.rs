@@ -0,0 +1,156 @@
+use crate::draw::{glyph_path, PathOp};
+use ab_glyph as ab;
+use std::fmt::Write as _;
+
+pub(crate) struct SvgDocument {
+    width: u32,
+    height: u32,
+    txt_color: [u8; 4],
+    bkg_color: [u8; 4],
+    paths: String,
+}
+
+impl SvgDocument {
+    pub fn new(
+        width: u32,
+        height: u32,
+        txt_color: [u8; 4],
+        bkg_color: [u8; 4],
+    ) -> Self {
+        Self {
+            width,
+            height,
+            txt_color,
+            bkg_color,
+            paths: String::new(),
+        }
+    }
+
+    /// Emits the glyph outline as a path. The outline is taken from `font` as is,
+    /// so the caller is responsible for setting the font's variations beforehand.
+    pub fn push_glyph(
+        &mut self,
+        font: &impl ab::Font,
+        id: ab::GlyphId,
+        position: ab::Point,
+        scale_factor: ab::PxScaleFactor,
+    ) {
+        let Some(path) = glyph_path(font, id, position, scale_factor) else {
+            return;
+        };
+
+        let mut data = String::new();
+        for op in path {
+            _ = match op {
+                PathOp::MoveTo(p) => write!(data, "M{:.2} {:.2}", p.x, p.y),
+                PathOp::LineTo(p) => write!(data, "L{:.2} {:.2}", p.x, p.y),
+                PathOp::QuadTo(c, p) => {
+                    write!(data, "Q{:.2} {:.2} {:.2} {:.2}", c.x, c.y, p.x, p.y)
+                }
+                PathOp::CubicTo(c1, c2, p) => write!(
+                    data,
+                    "C{:.2} {:.2} {:.2} {:.2} {:.2} {:.2}",
+                    c1.x, c1.y, c2.x, c2.y, p.x, p.y
+                ),
+                PathOp::Close => write!(data, "Z"),
+            };
+        }
+
+        _ = writeln!(self.paths, r#"<path d="{data}"/>"#);
+    }
+
+    pub fn finish(self) -> String {
+        let [r, g, b, a] = self.bkg_color;
+        let background = format!(
+            r#"<rect width="100%" height="100%" fill="rgb({r},{g},{b})" fill-opacity="{:.3}"/>"#,
+            a as f32 / 255.0
+        );
+        let [r, g, b, a] = self.txt_color;
+
+        format!(
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+                "\n{background}\n",
+                r#"<g fill="rgb({r},{g},{b})" fill-opacity="{a:.3}">"#,
+                "\n{paths}</g>\n</svg>\n"
+            ),
+            w = self.width,
+            h = self.height,
+            background = background,
+            r = r,
+            g = g,
+            b = b,
+            a = a as f32 / 255.0,
+            paths = self.paths,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{layout, LayoutOptions},
+        logic::LastLine,
+    };
+    use ab_glyph::{Font as _, ScaleFont as _};
+
+    const RAQQ: &[u8] = include_bytes!("../fonts/Raqq.ttf");
+
+    #[test]
+    fn paths_are_drawn_where_the_layout_places_glyphs() {
+        let text = "بسم الله الرحمن الرحيم الحمد لله رب العالمين";
+        let options = LayoutOptions {
+            last_line: LastLine::Start,
+            ..LayoutOptions::new(600, 40.0)
+        };
+        let layout = layout(text, &[(RAQQ, 0)], options).unwrap();
+        let font = ab::FontRef::try_from_slice(RAQQ).unwrap();
+        let scale = font.pt_to_px_scale(40.0).unwrap();
+
+        let mut document = SvgDocument::new(600, layout.height(), [0; 4], [255; 4]);
+        // the pixel bounds the PNG output draws each outlined glyph in
+        let mut bounds = vec![];
+        for g in layout.lines.iter().flat_map(|line| &line.glyphs) {
+            let position = ab::point(g.x, g.y);
+            let id = ab::GlyphId(g.id);
+            document.push_glyph(&font, id, position, font.as_scaled(scale).scale_factor());
+            if let Some(outlined) = font.outline_glyph(id.with_scale_and_position(scale, position))
+            {
+                bounds.push(outlined.px_bounds());
+            }
+        }
+
+        let svg = document.finish();
+        let paths = svg
+            .lines()
+            .filter_map(|l| l.strip_prefix(r#"<path d=""#)?.strip_suffix(r#""/>"#))
+            .collect::<Vec<_>>();
+        assert!(bounds.len() > 10);
+        assert_eq!(paths.len(), bounds.len());
+
+        for (data, bb) in paths.iter().zip(&bounds) {
+            let numbers = data
+                .split(|c: char| c.is_ascii_alphabetic() || c == ' ')
+                .filter(|n| !n.is_empty())
+                .map(|n| n.parse::<f32>().unwrap())
+                .collect::<Vec<_>>();
+            let (xs, ys) = (numbers.iter().step_by(2), numbers.iter().skip(1).step_by(2));
+            let min_x = xs.clone().copied().fold(f32::INFINITY, f32::min);
+            let max_x = xs.copied().fold(f32::NEG_INFINITY, f32::max);
+            let min_y = ys.clone().copied().fold(f32::INFINITY, f32::min);
+            let max_y = ys.copied().fold(f32::NEG_INFINITY, f32::max);
+
+            // pixel bounds are the outline's rounded outwards to whole pixels
+            let within = |path: f32, pixel: f32| (path - pixel).abs() <= 1.01;
+            assert!(
+                within(min_x, bb.min.x) && within(max_x, bb.max.x),
+                "{data} {bb:?}"
+            );
+            assert!(
+                within(min_y, bb.min.y) && within(max_y, bb.max.y),
+                "{data} {bb:?}"
+            );
+        }
+    }
+}